@@ -54,8 +54,13 @@ class ForegroundService : Service(), SharedPreferences.OnSharedPreferenceChangeL
     }
 
     private external fun mTrackFunction(
-        path: String,
+        paths: Array<String>,
         ext: String,
+        title: String,
+        artist: String,
+        album: String,
+        cuePath: String,
+        cueOffset: Int,
         dur: Int,
         pos: Int,
         metadataReqs: Byte
@@ -67,6 +72,31 @@ class ForegroundService : Service(), SharedPreferences.OnSharedPreferenceChangeL
 
     private external fun setToken(token: String)
 
+    /** Returns why the rules are invalid, or an empty string */
+    private external fun setRules(json: String): String
+
+    /**
+     * Overrides for files that can't be retagged, as JSON like
+     * `{"match": {"release_mbid": "…"}, "set": {"album": "…"}}`. Adding returns why the
+     * override is invalid, or an empty string.
+     */
+    external fun addOverride(json: String): String
+
+    /** Every override, as a JSON array */
+    external fun listOverrides(): String
+
+    /** Removes the override with the given match, like `{"path": "…"}` */
+    external fun removeOverride(key: String): Boolean
+
+    /**
+     * Listens the server rejected for good, as a JSON array of
+     * `{"id", "error", "quarantined_at", "listen"}`
+     */
+    external fun listQuarantined(): String
+
+    /** Discards the rejected listen with the given id */
+    external fun removeQuarantined(id: String): Boolean
+
     override fun onDestroy() {
         super.onDestroy()
         isStarted = false
@@ -93,6 +123,7 @@ class ForegroundService : Service(), SharedPreferences.OnSharedPreferenceChangeL
             val service = getSystemService(Context.NOTIFICATION_SERVICE) as NotificationManager
             service.createNotificationChannel(chan)
             service.createNotificationChannel(error_chan)
+            loadRules()
             threadStopped()
 
             val mTrackReceiver: BroadcastReceiver = object : BroadcastReceiver() {
@@ -106,41 +137,59 @@ class ForegroundService : Service(), SharedPreferences.OnSharedPreferenceChangeL
                             mPath.indexOf("/")
                         ) + ":" + mPath.substring(mPath.indexOf("/") + 1)
                         Log.v("ForegroundService", mPath)
-                        applicationContext.contentResolver.persistedUriPermissions.forEach {
-                            val path = openContentFd(
-                                DocumentsContract.buildChildDocumentsUriUsingTree(it.uri, mPath)
-                            )
-                            val sharedPreferences = PreferenceManager.getDefaultSharedPreferences(applicationContext)
-                            val reqs = MetadataReqFlag.none()
-
-                            if (sharedPreferences.getBoolean("artist_req", false)) {
-                                reqs.add(MetadataReqFlag.Artist)
-                            }
-                            if (sharedPreferences.getBoolean("title_req", false)) {
-                                reqs.add(MetadataReqFlag.Title)
-                            }
-                            if (sharedPreferences.getBoolean("album_req", false)) {
-                                reqs.add(MetadataReqFlag.Album)
-                            }
-                            if (sharedPreferences.getBoolean("release_mbid_req", false)) {
-                                reqs.add(MetadataReqFlag.ReleaseMBID)
-                            }
-                            if (sharedPreferences.getBoolean("artist_mbid_req", false)) {
-                                reqs.add(MetadataReqFlag.ArtistMBIDS)
-                            }
-                            if (sharedPreferences.getBoolean("recording_mbid_req", false)) {
-                                reqs.add(MetadataReqFlag.RecordingMBID)
-                            }
-                            if (path != null) {
-                                val ext = mPath.substring(mPath.lastIndexOf(".") + 1)
-                                val dur = mCurrentTrack.getInt("durMs", -1)
-                                val pos = intent.getIntExtra("pos", 0)
-                                Log.v("ForegroundService", "Pos: $pos")
-                                mTrackFunction(path, ext, dur, pos, reqs.toBits())
-                                return
-                            }
+                        val sharedPreferences = PreferenceManager.getDefaultSharedPreferences(applicationContext)
+                        val reqs = MetadataReqFlag.none()
+
+                        if (sharedPreferences.getBoolean("artist_req", false)) {
+                            reqs.add(MetadataReqFlag.Artist)
+                        }
+                        if (sharedPreferences.getBoolean("title_req", false)) {
+                            reqs.add(MetadataReqFlag.Title)
+                        }
+                        if (sharedPreferences.getBoolean("album_req", false)) {
+                            reqs.add(MetadataReqFlag.Album)
                         }
-                        notScrobbling()
+                        if (sharedPreferences.getBoolean("release_mbid_req", false)) {
+                            reqs.add(MetadataReqFlag.ReleaseMBID)
+                        }
+                        if (sharedPreferences.getBoolean("artist_mbid_req", false)) {
+                            reqs.add(MetadataReqFlag.ArtistMBIDS)
+                        }
+                        if (sharedPreferences.getBoolean("recording_mbid_req", false)) {
+                            reqs.add(MetadataReqFlag.RecordingMBID)
+                        }
+                        val ext = mPath.substring(mPath.lastIndexOf(".") + 1)
+                        val isCueSheet = ext.equals("cue", ignoreCase = true)
+                        // Every library root can have a file at this path, the native side picks
+                        // the one matching the track. None tells it to go by PowerAmp's metadata
+                        // alone. A CUE sheet has no tags to read, the native side finds the file it
+                        // names next to it.
+                        val paths = if (isCueSheet) emptyArray() else resolveLibraryPaths(mPath)
+                        // CUE tracks of a single-file album come with their offset into the file,
+                        // and the sheet is either embedded or next to it with the same name
+                        val isCue = mCurrentTrack.getBoolean("isCue", false)
+                        val cuePath = when {
+                            !isCue -> ""
+                            isCueSheet -> resolveLibraryPath(mPath).orEmpty()
+                            else -> resolveLibraryPath(mPath.substringBeforeLast(".") + ".cue").orEmpty()
+                        }
+                        val cueOffset = if (isCue) mCurrentTrack.getInt("cueOffsetMs", -1) else -1
+                        val dur = mCurrentTrack.getInt("durMs", -1)
+                        val pos = intent.getIntExtra("pos", 0)
+                        Log.v("ForegroundService", "Pos: $pos")
+                        mTrackFunction(
+                            paths,
+                            ext,
+                            mCurrentTrack.getString("title").orEmpty(),
+                            mCurrentTrack.getString("artist").orEmpty(),
+                            mCurrentTrack.getString("album").orEmpty(),
+                            cuePath,
+                            cueOffset,
+                            dur,
+                            pos,
+                            reqs.toBits()
+                        )
+                        return
                     }
 
                     // processTrackIntent()
@@ -273,6 +322,19 @@ class ForegroundService : Service(), SharedPreferences.OnSharedPreferenceChangeL
         manager.notify(errNotifyNum, notification)
     }
 
+    /** Rewrite rules come from the preference, or rules.json in the app's external files */
+    private fun loadRules() {
+        val sharedPreferences = PreferenceManager.getDefaultSharedPreferences(this)
+        val json = sharedPreferences.getString("rewrite_rules", "").orEmpty().ifBlank {
+            val file = File(getExternalFilesDir(null), "rules.json")
+            if (file.isFile) file.readText() else ""
+        }
+        val error = setRules(json)
+        if (error.isNotEmpty()) {
+            crashNotify("Rewrite rules: $error")
+        }
+    }
+
     fun getToken(): String {
         val sharedPreferences = PreferenceManager.getDefaultSharedPreferences(this)
         return "Token " + sharedPreferences.getString("token", "")
@@ -282,6 +344,20 @@ class ForegroundService : Service(), SharedPreferences.OnSharedPreferenceChangeL
         return cacheDir.absolutePath.toString()
     }
 
+    fun getFiles(): String {
+        return filesDir.absolutePath.toString()
+    }
+
+    fun getPreferenceString(key: String, default: String): String {
+        val sharedPreferences = PreferenceManager.getDefaultSharedPreferences(this)
+        return sharedPreferences.getString(key, default) ?: default
+    }
+
+    fun getPreferenceBoolean(key: String, default: Boolean): Boolean {
+        val sharedPreferences = PreferenceManager.getDefaultSharedPreferences(this)
+        return sharedPreferences.getBoolean(key, default)
+    }
+
     /*
     private fun parsePathFromIntent(intent: Intent): String? {
         val filepath: String?
@@ -297,6 +373,21 @@ class ForegroundService : Service(), SharedPreferences.OnSharedPreferenceChangeL
     }
     */
 
+    private fun resolveLibraryPaths(mPath: String): Array<String> {
+        return applicationContext.contentResolver.persistedUriPermissions
+            .mapNotNull {
+                openContentFd(DocumentsContract.buildChildDocumentsUriUsingTree(it.uri, mPath))
+            }
+            .toTypedArray()
+    }
+
+    private fun resolveLibraryPath(mPath: String): String? {
+        return applicationContext.contentResolver.persistedUriPermissions
+            .firstNotNullOfOrNull {
+                openContentFd(DocumentsContract.buildChildDocumentsUriUsingTree(it.uri, mPath))
+            }
+    }
+
     private fun openContentFd(uri: Uri): String? {
         val resolver = applicationContext.contentResolver
         Log.v("ForegroundService", "Resolving content URI: $uri")
@@ -340,5 +431,8 @@ class ForegroundService : Service(), SharedPreferences.OnSharedPreferenceChangeL
         if (key == "token") {
             setToken(getToken())
         }
+        if (key == "rewrite_rules") {
+            loadRules()
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,97 @@
+//! Compares the tag block readers against the full tag library backends the crate was built
+//! with.
+//!
+//! Synthetic fixtures with large cover art are generated for every container the tag block
+//! readers handle. Point `LBP_BENCH_CORPUS` at a directory of real files to time those too.
+//!
+//!     cargo bench --bench tag_readers --all-features
+
+use std::{
+    fs::File,
+    hint::black_box,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use lbp_native::tags::{self, TagReader};
+
+#[path = "../src/tags/fixtures.rs"]
+mod fixtures;
+
+const ITERATIONS: u32 = 200;
+
+const READERS: &[&dyn TagReader] = &[
+    &tags::TagBlocks,
+    #[cfg(feature = "lofty")]
+    &tags::lofty::Lofty,
+    #[cfg(feature = "symphonia")]
+    &tags::symphonia::Symphonia,
+];
+
+fn main() {
+    let dir = std::env::temp_dir().join("lbp_tag_fixtures");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut fixtures = write_fixtures(&dir);
+
+    if let Ok(corpus) = std::env::var("LBP_BENCH_CORPUS") {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(corpus)
+            .unwrap()
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+        fixtures.extend(files);
+    }
+
+    print!("{:<32}", "fixture");
+    for reader in READERS {
+        print!(" {:>12} {:>6}", reader.name(), "tags");
+    }
+    println!();
+    for path in fixtures {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        print!("{:<32}", name);
+        for reader in READERS {
+            // Files a reader doesn't support or fails on show up without a tag count
+            let (elapsed, tags) = time(|| {
+                let file = File::open(&path).unwrap();
+                reader
+                    .read_tags(&file, &ext)
+                    .ok()
+                    .flatten()
+                    .map(|tags| tags.len())
+            });
+            print!(" {:>12} {:>6}", format!("{:.1?}", elapsed), count(tags));
+        }
+        println!();
+    }
+}
+
+fn time(mut f: impl FnMut() -> Option<usize>) -> (Duration, Option<usize>) {
+    let result = f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    (start.elapsed() / ITERATIONS, result)
+}
+
+fn count(tags: Option<usize>) -> String {
+    tags.map_or_else(|| "-".to_string(), |n| n.to_string())
+}
+
+fn write_fixtures(dir: &Path) -> Vec<PathBuf> {
+    fixtures::all()
+        .into_iter()
+        .map(|(name, data)| {
+            let path = dir.join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        })
+        .collect()
+}
@@ -0,0 +1,417 @@
+//! Identifies files with no usable tags by their audio, through an AcoustID compatible service.
+//! The start of the file is decoded and fingerprinted in the background, and fingerprints are
+//! kept on disk next to what they matched so each file is only ever fingerprinted once.
+
+use std::{
+    fs::File,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chromaprint,
+    disk_cache::{self, now_secs, FileVersion},
+    submission::http_client,
+    TrackMetadata,
+};
+
+pub const DEFAULT_URL: &str = "https://api.acoustid.org/v2/lookup";
+
+/// How much of the file is fingerprinted, the same as `fpcalc`
+#[cfg(feature = "symphonia")]
+const FINGERPRINT_LENGTH: Duration = Duration::from_secs(120);
+
+/// Matches scoring lower than this are someone else's recording
+const MIN_SCORE: f64 = 0.7;
+
+/// How long a lookup that found nothing is trusted, new fingerprints are submitted all the time
+const NEGATIVE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Entries are named after the path alone, so a changed file overwrites its stale entry
+fn cache_file(cache_dir: &Path, key: &FileVersion) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    key.path.hash(&mut hasher);
+    cache_dir.join(format!("{:016x}.json", hasher.finish()))
+}
+
+/// A file to identify and the service to ask
+#[derive(Debug)]
+pub struct Request {
+    file: File,
+    ext: String,
+    key: Option<FileVersion>,
+    duration: Duration,
+    url: String,
+    api_key: String,
+}
+
+impl Request {
+    pub fn new(
+        file: File,
+        library_path: &Path,
+        ext: &str,
+        duration: Duration,
+        url: &str,
+        api_key: &str,
+    ) -> Self {
+        Self {
+            key: FileVersion::new(&file, library_path),
+            file,
+            ext: ext.to_string(),
+            duration,
+            url: url.to_string(),
+            api_key: api_key.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FingerprintMatch {
+    pub recording_mbid: String,
+    pub title: String,
+    pub artist_name: String,
+    pub artist_mbids: Vec<String>,
+}
+
+impl FingerprintMatch {
+    /// Fills in whichever fields the track doesn't have. Names it already has stay even if they
+    /// were only guessed from the path, overrides and rules may have put them there.
+    pub fn fill(&self, track_metadata: &mut TrackMetadata) {
+        let fill = |field: &mut String, value: &String| {
+            if field.is_empty() {
+                field.clone_from(value);
+            }
+        };
+        let additional_info = &mut track_metadata.additional_info;
+        fill(&mut additional_info.recording_mbid, &self.recording_mbid);
+        if additional_info.artist_mbids.is_empty() {
+            additional_info.artist_mbids.clone_from(&self.artist_mbids);
+        }
+        fill(&mut track_metadata.track_name, &self.title);
+        fill(&mut track_metadata.artist_name, &self.artist_name);
+    }
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    status: String,
+    #[serde(default)]
+    error: Option<ResponseError>,
+    #[serde(default)]
+    results: Vec<LookupResult>,
+}
+
+#[derive(Deserialize)]
+struct ResponseError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct LookupResult {
+    score: f64,
+    #[serde(default)]
+    recordings: Vec<Recording>,
+}
+
+#[derive(Deserialize)]
+struct Recording {
+    id: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    artists: Vec<Artist>,
+}
+
+#[derive(Deserialize)]
+struct Artist {
+    id: String,
+    name: String,
+    #[serde(default)]
+    joinphrase: Option<String>,
+}
+
+impl LookupResponse {
+    /// The best scoring result's first recording that has names, if it scores high enough
+    fn into_match(self) -> Option<FingerprintMatch> {
+        let best = self
+            .results
+            .into_iter()
+            .filter(|result| result.score >= MIN_SCORE)
+            .max_by(|a, b| a.score.total_cmp(&b.score))?;
+        let recording = best
+            .recordings
+            .into_iter()
+            .find(|recording| !recording.title.is_empty() && !recording.artists.is_empty())?;
+        let mut artist_name = String::new();
+        let last = recording.artists.len() - 1;
+        for (i, artist) in recording.artists.iter().enumerate() {
+            artist_name.push_str(&artist.name);
+            let default_join = if i < last { " & " } else { "" };
+            artist_name.push_str(artist.joinphrase.as_deref().unwrap_or(default_join));
+        }
+        Some(FingerprintMatch {
+            recording_mbid: recording.id,
+            title: recording.title,
+            artist_name,
+            artist_mbids: recording
+                .artists
+                .into_iter()
+                .map(|artist| artist.id)
+                .collect(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    key: FileVersion,
+    fingerprint: String,
+    /// `None` until the service has answered
+    lookup: Option<CachedLookup>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedLookup {
+    result: Option<FingerprintMatch>,
+    looked_up_at: u64,
+}
+
+fn read_cache(path: &Path, key: &FileVersion) -> Option<CacheEntry> {
+    let entry: CacheEntry = disk_cache::read_entry(path)?;
+    (entry.key == *key).then_some(entry)
+}
+
+/// Identifies a file by its audio. `None` when it couldn't be fingerprinted, there's no
+/// confident match or the lookup failed, failures aren't cached so the next play tries again.
+pub async fn lookup(request: Request, cache_dir: &Path) -> Option<FingerprintMatch> {
+    let Request {
+        file,
+        ext,
+        key,
+        duration,
+        url,
+        api_key,
+    } = request;
+    let path = key
+        .as_ref()
+        .map(|key| cache_file(cache_dir, key))
+        .unwrap_or_default();
+    let cached = key.as_ref().and_then(|key| read_cache(&path, key));
+    let fingerprint = match cached {
+        Some(CacheEntry {
+            lookup: Some(lookup),
+            ..
+        }) if lookup.result.is_some()
+            || now_secs().saturating_sub(lookup.looked_up_at) <= NEGATIVE_TTL.as_secs() =>
+        {
+            log::debug!("Cached fingerprint lookup: {:?}", lookup.result);
+            return lookup.result;
+        }
+        Some(entry) => entry.fingerprint,
+        None => match tokio::task::spawn_blocking(move || fingerprint_file(file, &ext)).await {
+            Ok(Ok(Some(fingerprint))) => fingerprint,
+            Ok(Ok(None)) => {
+                log::info!("Couldn't decode enough of the file to fingerprint it");
+                return None;
+            }
+            Ok(Err(e)) => {
+                log::warn!("Fingerprinting failed: {}", e);
+                return None;
+            }
+            Err(e) => {
+                log::error!("Fingerprinting task failed: {}", e);
+                return None;
+            }
+        },
+    };
+
+    let duration = duration.as_secs().to_string();
+    let response = http_client()
+        .post(&url)
+        .form(&[
+            ("client", api_key.as_str()),
+            ("format", "json"),
+            ("meta", "recordings"),
+            ("duration", &duration),
+            ("fingerprint", &fingerprint),
+        ])
+        .send()
+        .await;
+    // Errors come with a status in the body too, which says more than the HTTP one
+    let response = match response {
+        Ok(response) => response.json::<LookupResponse>().await,
+        Err(e) => Err(e),
+    };
+    let lookup = match response {
+        Ok(response) if response.status == "ok" => Some(CachedLookup {
+            result: response.into_match(),
+            looked_up_at: now_secs(),
+        }),
+        Ok(response) => {
+            let message = response.error.map(|error| error.message);
+            log::warn!("Fingerprint lookup failed: {}", message.unwrap_or_default());
+            None
+        }
+        Err(e) => {
+            log::warn!("Fingerprint lookup failed: {}", e);
+            None
+        }
+    };
+    if let Some(lookup) = &lookup {
+        log::info!("Fingerprint lookup: {:?}", lookup.result);
+    }
+
+    let Some(key) = key else {
+        return lookup.and_then(|lookup| lookup.result);
+    };
+    let entry = CacheEntry {
+        key,
+        fingerprint,
+        lookup,
+    };
+    disk_cache::write_entry(cache_dir, &path, &entry, "fingerprint");
+    entry.lookup.and_then(|lookup| lookup.result)
+}
+
+/// The compressed fingerprint of the start of a file, `None` when it's too short or can't be
+/// decoded
+fn fingerprint_file(file: File, ext: &str) -> io::Result<Option<String>> {
+    let Some(samples) = decode(file, ext)? else {
+        return Ok(None);
+    };
+    let fingerprint = chromaprint::fingerprint(&samples, chromaprint::SAMPLE_RATE);
+    Ok((!fingerprint.is_empty()).then(|| chromaprint::encode(&fingerprint)))
+}
+
+/// Decodes the start of the file to mono at the rate the fingerprint is taken at, a packet at a
+/// time so only that much is ever held
+#[cfg(feature = "symphonia")]
+fn decode(mut file: File, ext: &str) -> io::Result<Option<Vec<f32>>> {
+    use std::io::Seek;
+
+    use symphonia::core::{audio::SampleBuffer, codecs::DecoderOptions, errors::Error};
+
+    // Reading the tags left the descriptor wherever they ended
+    file.rewind()?;
+    let invalid = |e: Error| io::Error::new(io::ErrorKind::InvalidData, e);
+    let Some(mut probed) = crate::tags::symphonia::probe(file, ext)? else {
+        return Ok(None);
+    };
+    let Some(track) = probed.format.default_track() else {
+        return Ok(None);
+    };
+    let (track_id, params) = (track.id, track.codec_params.clone());
+    let Some(sample_rate) = params.sample_rate.filter(|&rate| rate > 0) else {
+        return Ok(None);
+    };
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&params, &DecoderOptions::default())
+        .map_err(invalid)?;
+
+    let limit = FINGERPRINT_LENGTH.as_secs() as usize * chromaprint::SAMPLE_RATE as usize;
+    let mut samples = Vec::with_capacity(limit);
+    let mut resampler = chromaprint::Resampler::new(sample_rate);
+    let mut mono = Vec::new();
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    while samples.len() < limit {
+        let packet = match probed.format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(Error::IoError(e)) => return Err(e),
+            Err(e) => return Err(invalid(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A damaged packet is a moment of silence to a player
+            Err(Error::DecodeError(e)) => {
+                log::debug!("Skipping undecodable packet: {}", e);
+                continue;
+            }
+            Err(Error::IoError(e)) => return Err(e),
+            Err(e) => return Err(invalid(e)),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        let needed = decoded.capacity() * channels;
+        let buffer = match &mut buffer {
+            Some(buffer) if buffer.capacity() >= needed => buffer,
+            _ => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        mono.clear();
+        mono.extend(
+            buffer
+                .samples()
+                .chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+        resampler.push(&mono, &mut samples);
+    }
+    resampler.finish(&mut samples);
+    samples.truncate(limit);
+    Ok(Some(samples))
+}
+
+/// Decoding needs the symphonia backend
+#[cfg(not(feature = "symphonia"))]
+fn decode(_file: File, _ext: &str) -> io::Result<Option<Vec<f32>>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(json: &str) -> LookupResponse {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn ignores_low_scores() {
+        let response = response(
+            r#"{"status": "ok", "results": [
+                {"score": 0.5, "recordings": [{"id": "a", "title": "Low", "artists": [{"id": "x", "name": "X"}]}]},
+                {"score": 0.9, "recordings": [{"id": "b"}, {"id": "c", "title": "High", "artists": [{"id": "y", "name": "Y"}]}]},
+                {"score": 0.8, "recordings": [{"id": "d", "title": "Lower", "artists": [{"id": "z", "name": "Z"}]}]}
+            ]}"#,
+        );
+        let found = response.into_match().unwrap();
+        assert_eq!(found.recording_mbid, "c");
+        assert_eq!(found.title, "High");
+
+        let response = self::response(
+            r#"{"status": "ok", "results": [
+                {"score": 0.69, "recordings": [{"id": "a", "title": "Low", "artists": [{"id": "x", "name": "X"}]}]}
+            ]}"#,
+        );
+        assert!(response.into_match().is_none());
+    }
+
+    #[test]
+    fn joins_artist_names() {
+        let response = response(
+            r#"{"status": "ok", "results": [{"score": 1.0, "recordings": [{
+                "id": "r",
+                "title": "Get Lucky",
+                "artists": [
+                    {"id": "a", "name": "Daft Punk", "joinphrase": " feat. "},
+                    {"id": "b", "name": "Pharrell Williams"},
+                    {"id": "c", "name": "Nile Rodgers"}
+                ]
+            }]}]}"#,
+        );
+        let found = response.into_match().unwrap();
+        assert_eq!(
+            found.artist_name,
+            "Daft Punk feat. Pharrell Williams & Nile Rodgers"
+        );
+        assert_eq!(found.artist_mbids, ["a", "b", "c"]);
+    }
+}
@@ -0,0 +1,80 @@
+/// Separators used to cram several artists into a single tag value. Unlike the join phrases of a
+/// credit (" & ", " feat. "), these never belong in the submitted artist name.
+const VALUE_SEPARATORS: [&str; 3] = ["\0", ";", " / "];
+
+/// Collects the artist tags of a file and turns them into one ListenBrainz artist credit
+#[derive(Debug, Default)]
+pub struct ArtistCredits {
+    /// ARTIST / TPE1 / ©ART values, in tag order
+    artist: Vec<String>,
+    /// Picard's multi-valued ARTISTS tag, in tag order
+    artists: Vec<String>,
+}
+
+impl ArtistCredits {
+    pub fn push_artist(&mut self, value: String) {
+        push_unique(&mut self.artist, value);
+    }
+
+    pub fn push_artists(&mut self, value: String) {
+        push_unique(&mut self.artists, value);
+    }
+
+    /// The individual credited artists, in the same order as their MBIDs are tagged
+    pub fn names(&self) -> Vec<String> {
+        let source = if self.artists.is_empty() {
+            &self.artist
+        } else {
+            &self.artists
+        };
+        let mut names = Vec::new();
+        for value in source {
+            for name in split_value(value) {
+                push_unique(&mut names, name.to_string());
+            }
+        }
+        names
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.artist.is_empty() && self.artists.is_empty()
+    }
+
+    /// The artist name to submit
+    pub fn credit(&self) -> String {
+        // A single ARTIST value is already the credit as the tagger wrote it, join phrases and all
+        if let [artist] = self.artist.as_slice() {
+            if !VALUE_SEPARATORS.iter().any(|sep| artist.contains(sep)) {
+                return artist.clone();
+            }
+        }
+        join_credit(&self.names())
+    }
+}
+
+fn split_value(value: &str) -> impl Iterator<Item = &str> {
+    let mut parts = vec![value];
+    for sep in VALUE_SEPARATORS {
+        parts = parts.into_iter().flat_map(|part| part.split(sep)).collect();
+    }
+    parts
+        .into_iter()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+}
+
+/// "A", "A & B", "A, B & C"
+fn join_credit(names: &[String]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => name.clone(),
+        [rest @ .., last] => format!("{} & {}", rest.join(", "), last),
+    }
+}
+
+/// Appends `value` unless it's already present, keeping the original order
+pub fn push_unique(values: &mut Vec<String>, value: String) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
@@ -0,0 +1,324 @@
+//! Picking the file that's actually playing. The service hands over the track's path resolved
+//! under every library root, and roots can share relative paths.
+
+use std::{fs::File, io, path::PathBuf, time::Duration};
+
+use crate::{
+    cue, library_path,
+    metadata::MetadataBuilder,
+    metadata_cache::{CacheKey, MetadataCache},
+    open_path,
+    path_template::PathTemplate,
+    tag_value::comparable,
+    tags::{self, Tag, TagKey},
+    TrackMetadata, MAX_DURATION,
+};
+
+/// Durations this close are the same track, give or take encoder padding
+const DURATION_MATCH: Duration = Duration::from_secs(3);
+/// Durations this far apart are a different track
+const DURATION_MISMATCH: Duration = Duration::from_secs(10);
+
+/// A file the track could be and what it says about it
+#[derive(Debug)]
+pub struct Candidate {
+    pub path: String,
+    /// Where a descriptor pointed, the file stays open in `file`
+    pub library_path: PathBuf,
+    pub file: File,
+    pub track_metadata: TrackMetadata,
+    /// The file has no tags naming the track, its metadata is all inferred
+    pub untagged: bool,
+    file_duration: Option<Duration>,
+}
+
+/// Everything extracting a candidate's metadata depends on besides the file itself
+pub struct Extraction<'a> {
+    pub ext: &'a str,
+    pub cue_sheet: Option<&'a str>,
+    pub cue_offset: Option<Duration>,
+    pub template: &'a str,
+    pub title: &'a str,
+    pub reported_duration: Option<Duration>,
+    pub cache: &'a MetadataCache,
+    /// Reading the duration means probing the file, so only when there's a choice to make
+    pub compare_durations: bool,
+}
+
+impl Extraction<'_> {
+    /// Reads a candidate's metadata, from the cache if the file hasn't changed since
+    pub fn extract(&self, path: &str) -> io::Result<Candidate> {
+        let file = unsafe { open_path(path) }?;
+        let library_path = library_path(path);
+        // A CUE track's file is the whole album, its length says nothing about the track
+        let file_duration =
+            || tags::read_duration(&file, self.ext).filter(|duration| *duration <= MAX_DURATION);
+        let compared_duration = (self.compare_durations
+            && self.reported_duration.is_some()
+            && self.cue_offset.is_none())
+        .then(file_duration)
+        .flatten();
+        // Kept out of the cache, PowerAmp may know the duration next time even if it didn't now
+        let duration = || {
+            let mut duration = self.reported_duration;
+            if duration.is_none() && self.cue_offset.is_none() {
+                duration = compared_duration.or_else(file_duration);
+                log::info!("Duration from the file: {:?}", duration);
+            }
+            duration.map_or(0, |duration| duration.as_millis() as u64)
+        };
+
+        let cache_key = CacheKey::new(
+            &file,
+            &library_path,
+            self.cue_offset,
+            self.cue_sheet,
+            self.template,
+        );
+        if let Some((mut track_metadata, untagged)) =
+            cache_key.as_ref().and_then(|key| self.cache.get(key))
+        {
+            log::debug!("Using cached metadata for {}", path);
+            track_metadata.additional_info.duration_ms = duration();
+            return Ok(Candidate {
+                path: path.to_string(),
+                library_path,
+                file,
+                track_metadata,
+                untagged,
+                file_duration: compared_duration,
+            });
+        }
+
+        let mut tags = tags::read_file(&file, self.ext);
+        cue::apply(
+            &mut tags,
+            self.cue_sheet,
+            &library_path,
+            self.cue_offset,
+            self.title,
+        );
+        let untagged = !tags.iter().any(names_track);
+
+        let mut builder = MetadataBuilder::new(0);
+        for tag in tags {
+            builder.add(tag);
+        }
+        if !self.template.is_empty() {
+            match PathTemplate::parse(self.template) {
+                Ok(template) => {
+                    for tag in template.infer(&library_path) {
+                        builder.add_inferred(tag);
+                    }
+                }
+                Err(e) => log::warn!("Invalid path template {:?}: {}", self.template, e),
+            }
+        }
+        let mut track_metadata = builder.finish();
+        if let Some(key) = cache_key {
+            self.cache.insert(key, &track_metadata, untagged);
+        }
+        track_metadata.additional_info.duration_ms = duration();
+        Ok(Candidate {
+            path: path.to_string(),
+            library_path,
+            file,
+            track_metadata,
+            untagged,
+            file_duration: compared_duration,
+        })
+    }
+}
+
+/// Whether a tag says which track the file is
+fn names_track(tag: &Tag) -> bool {
+    matches!(
+        tag.key,
+        TagKey::Title | TagKey::Artist | TagKey::Artists | TagKey::RecordingMbid
+    ) && !tag.value.trim().is_empty()
+}
+
+/// How well a candidate agrees with what PowerAmp says is playing
+#[derive(Debug)]
+struct Score {
+    /// One for each of the title and duration that match and minus one for each that clearly
+    /// doesn't
+    points: i32,
+    /// Nothing matches and something doesn't
+    mismatch: bool,
+}
+
+impl Candidate {
+    fn score(&self, title: &str, reported_duration: Option<Duration>) -> Score {
+        let title_match = {
+            let (ours, theirs) = (
+                comparable(&self.track_metadata.track_name),
+                comparable(title),
+            );
+            (!ours.is_empty() && !theirs.is_empty())
+                .then(|| ours.contains(&theirs) || theirs.contains(&ours))
+        };
+        let duration_match =
+            self.file_duration
+                .zip(reported_duration)
+                .and_then(|(ours, theirs)| match ours.abs_diff(theirs) {
+                    diff if diff <= DURATION_MATCH => Some(true),
+                    diff if diff > DURATION_MISMATCH => Some(false),
+                    _ => None,
+                });
+
+        let checks = [title_match, duration_match];
+        let matches = checks.iter().filter(|&&check| check == Some(true)).count() as i32;
+        let mismatches = checks.iter().filter(|&&check| check == Some(false)).count() as i32;
+        Score {
+            points: matches - mismatches,
+            mismatch: matches == 0 && mismatches > 0,
+        }
+    }
+}
+
+/// The candidate that best matches what PowerAmp says is playing, or `None` when even the best
+/// clearly isn't it. A lone candidate is always kept, there's nothing to choose between and
+/// PowerAmp's titles differ from the tags' for remasters, featured artists and translations.
+pub fn pick(
+    candidates: Vec<Candidate>,
+    title: &str,
+    reported_duration: Option<Duration>,
+) -> Option<Candidate> {
+    let several = candidates.len() > 1;
+    let mut best: Option<(i32, Candidate)> = None;
+    for candidate in candidates {
+        let score = candidate.score(title, reported_duration);
+        log::debug!("{} scores {:?}", candidate.path, score);
+        if score.mismatch {
+            if several {
+                log::warn!(
+                    "{} doesn't match the playing track, its title is {:?}",
+                    candidate.path,
+                    candidate.track_metadata.track_name
+                );
+                continue;
+            }
+            log::info!(
+                "Keeping {} although its title {:?} differs from {:?}",
+                candidate.path,
+                candidate.track_metadata.track_name,
+                title
+            );
+        }
+        // The first root wins ties, it's the order the service always resolved in
+        if best
+            .as_ref()
+            .is_none_or(|(best_score, _)| score.points > *best_score)
+        {
+            best = Some((score.points, candidate));
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(path: &str, title: &str, file_duration: Option<Duration>) -> Candidate {
+        Candidate {
+            path: path.to_string(),
+            library_path: PathBuf::from(path),
+            file: File::open(std::env::current_exe().unwrap()).unwrap(),
+            track_metadata: TrackMetadata {
+                track_name: title.to_string(),
+                ..Default::default()
+            },
+            untagged: false,
+            file_duration,
+        }
+    }
+
+    fn picked(
+        candidates: Vec<Candidate>,
+        title: &str,
+        duration: Option<Duration>,
+    ) -> Option<String> {
+        pick(candidates, title, duration).map(|candidate| candidate.path)
+    }
+
+    #[test]
+    fn keeps_a_lone_candidate() {
+        let candidates = vec![candidate("/a", "Song (Remastered)", None)];
+        assert_eq!(picked(candidates, "Song", None).as_deref(), Some("/a"));
+        let candidates = vec![candidate("/a", "Lied", None)];
+        assert_eq!(picked(candidates, "Song", None).as_deref(), Some("/a"));
+    }
+
+    #[test]
+    fn picks_among_several() {
+        let minutes = |minutes: u64| Some(Duration::from_secs(minutes * 60));
+
+        // The title decides
+        let candidates = vec![
+            candidate("/a", "Other", None),
+            candidate("/b", "Song", None),
+        ];
+        assert_eq!(picked(candidates, "Song", None).as_deref(), Some("/b"));
+
+        // So does the duration
+        let candidates = vec![
+            candidate("/a", "Song", minutes(7)),
+            candidate("/b", "Song", minutes(3)),
+        ];
+        assert_eq!(
+            picked(candidates, "Song", minutes(3)).as_deref(),
+            Some("/b")
+        );
+
+        // Clear mismatches are ruled out even as the only ones left
+        let candidates = vec![
+            candidate("/a", "Other", minutes(7)),
+            candidate("/b", "Another", None),
+        ];
+        assert_eq!(picked(candidates, "Song", minutes(3)), None);
+
+        // Ties go to the first root
+        let candidates = vec![
+            candidate("/a", "Song", minutes(3)),
+            candidate("/b", "Song", minutes(3)),
+        ];
+        assert_eq!(
+            picked(candidates, "Song", minutes(3)).as_deref(),
+            Some("/a")
+        );
+    }
+
+    #[test]
+    fn duration_isnt_cached() {
+        let dir = std::env::temp_dir().join(format!("lbp-candidates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Artist - Title.wv");
+        std::fs::write(&path, b"not audio").unwrap();
+        let cache = MetadataCache::new(dir.join("cache"));
+        let mut extraction = Extraction {
+            ext: "wv",
+            cue_sheet: None,
+            cue_offset: None,
+            template: "%artist% - %title%",
+            title: "Title",
+            reported_duration: None,
+            cache: &cache,
+            compare_durations: false,
+        };
+
+        let candidate = extraction.extract(path.to_str().unwrap()).unwrap();
+        assert_eq!(candidate.track_metadata.additional_info.duration_ms, 0);
+        assert_eq!(candidate.track_metadata.track_name, "Title");
+
+        extraction.reported_duration = Some(Duration::from_secs(200));
+        let candidate = extraction.extract(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            candidate.track_metadata.additional_info.duration_ms,
+            200_000
+        );
+        assert_eq!(dir.join("cache").read_dir().unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,623 @@
+//! Chromaprint's default fingerprinting algorithm, the one AcoustID indexes. The audio is
+//! resampled to 11025 Hz, cut into overlapping frames whose spectrum is folded into the 12
+//! notes of the octave, and a fixed set of classifiers turns a sliding window of those into
+//! one 32 bit subfingerprint per frame.
+
+use std::{borrow::Cow, f64::consts::PI};
+
+pub const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+/// Frames overlap by two thirds
+const FRAME_STEP: usize = FRAME_SIZE / 3;
+const MIN_FREQ: f64 = 28.0;
+const MAX_FREQ: f64 = 3520.0;
+/// Note frequencies are counted from the A four octaves below 440 Hz
+const REFERENCE_FREQ: f64 = 440.0 / 16.0;
+const BANDS: usize = 12;
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+/// Chroma vectors quieter than this are silence
+const NORM_THRESHOLD: f64 = 0.01;
+/// The resampling filter's taps on each side, at the lower of the two rates
+const RESAMPLE_TAPS: f64 = 8.0;
+const RESAMPLE_CUTOFF: f64 = 0.8;
+/// How finely the resampling filter is tabulated between input samples
+const RESAMPLE_PHASES: usize = 256;
+/// Chromaprint's id for this algorithm in the compressed fingerprint
+const ALGORITHM: u8 = 1;
+
+#[derive(Clone, Copy)]
+enum FilterKind {
+    /// The whole area
+    Whole,
+    /// The upper half of the bands against the lower
+    Bands2,
+    /// The later half of the frames against the earlier
+    Frames2,
+    /// The diagonal quarters against each other
+    Quarters,
+    /// The middle third of the bands against the outer two
+    Bands3,
+    /// The middle third of the frames against the outer two
+    Frames3,
+}
+
+struct Classifier {
+    kind: FilterKind,
+    /// The first band
+    band: usize,
+    bands: usize,
+    frames: usize,
+    thresholds: [f64; 3],
+}
+
+const fn classifier(
+    kind: FilterKind,
+    band: usize,
+    bands: usize,
+    frames: usize,
+    thresholds: [f64; 3],
+) -> Classifier {
+    Classifier {
+        kind,
+        band,
+        bands,
+        frames,
+        thresholds,
+    }
+}
+
+/// Chromaprint's trained classifiers, each contributing two bits in this order
+#[rustfmt::skip]
+const CLASSIFIERS: [Classifier; 16] = [
+    classifier(FilterKind::Whole, 4, 3, 15, [1.98215, 2.35817, 2.63523]),
+    classifier(FilterKind::Bands3, 4, 6, 15, [-1.03809, -0.651211, -0.282167]),
+    classifier(FilterKind::Bands2, 0, 4, 16, [-0.298702, 0.119262, 0.558497]),
+    classifier(FilterKind::Quarters, 8, 2, 12, [-0.105439, 0.0153946, 0.135898]),
+    classifier(FilterKind::Quarters, 4, 4, 8, [-0.142891, 0.0258736, 0.200632]),
+    classifier(FilterKind::Bands3, 0, 3, 5, [-0.826319, -0.590612, -0.368214]),
+    classifier(FilterKind::Bands2, 2, 2, 9, [-0.557409, -0.233035, 0.0534525]),
+    classifier(FilterKind::Frames2, 7, 3, 4, [-0.0646826, 0.00620476, 0.0784847]),
+    classifier(FilterKind::Frames2, 6, 2, 16, [-0.192387, -0.029699, 0.215855]),
+    classifier(FilterKind::Frames2, 1, 3, 2, [-0.0397818, -0.00568076, 0.0292026]),
+    classifier(FilterKind::Frames3, 10, 1, 15, [-0.53823, -0.369934, -0.190235]),
+    classifier(FilterKind::Quarters, 6, 2, 10, [-0.124877, 0.0296483, 0.139239]),
+    classifier(FilterKind::Frames2, 1, 1, 14, [-0.101475, 0.0225617, 0.231971]),
+    classifier(FilterKind::Quarters, 5, 6, 4, [-0.0799915, -0.00729616, 0.063262]),
+    classifier(FilterKind::Bands2, 9, 2, 12, [-0.272556, 0.019424, 0.302559]),
+    classifier(FilterKind::Quarters, 4, 2, 14, [-0.164292, -0.0321188, 0.08463]),
+];
+
+/// How many frames the widest classifier looks at
+const MAX_FRAMES: usize = 16;
+
+/// Fingerprints mono audio, one subfingerprint for every frame past the first few
+pub fn fingerprint(samples: &[f32], sample_rate: u32) -> Vec<u32> {
+    let samples = resample(samples, sample_rate);
+    let chroma = chroma(&samples);
+
+    // The filter only starts once its buffer is full, which leaves out the first frame too
+    let filtered: Vec<[f64; BANDS]> = chroma
+        .windows(CHROMA_FILTER.len())
+        .skip(1)
+        .map(|window| {
+            let mut row = [0.0; BANDS];
+            for (frame, coefficient) in window.iter().zip(CHROMA_FILTER) {
+                for (value, energy) in row.iter_mut().zip(frame) {
+                    *value += energy * coefficient;
+                }
+            }
+            normalize(&mut row);
+            row
+        })
+        .collect();
+
+    let image = IntegralImage::new(&filtered);
+    (0..(image.rows.len() + 1).saturating_sub(MAX_FRAMES))
+        .map(|frame| {
+            CLASSIFIERS.iter().fold(0, |bits, classifier| {
+                (bits << 2) | gray_code(classifier.classify(&image, frame))
+            })
+        })
+        .collect()
+}
+
+/// Resamples to the rate the algorithm works at in one go
+fn resample(samples: &[f32], sample_rate: u32) -> Cow<'_, [f32]> {
+    if sample_rate == SAMPLE_RATE {
+        return Cow::Borrowed(samples);
+    }
+    let mut resampler = Resampler::new(sample_rate);
+    let mut output = Vec::new();
+    resampler.push(samples, &mut output);
+    resampler.finish(&mut output);
+    Cow::Owned(output)
+}
+
+/// Resamples to the rate the algorithm works at with a windowed sinc filter, a block of input
+/// at a time so only the filter's reach of it is ever kept
+pub struct Resampler {
+    step: f64,
+    reach: usize,
+    /// The filter at every fraction of an input sample an output one can fall on, computing
+    /// it for each output sample takes longer than the rest of the fingerprint. Empty when the
+    /// input is already at that rate.
+    kernels: Vec<Vec<f32>>,
+    /// The input the next output samples still need, from input sample `start` on
+    input: Vec<f32>,
+    start: usize,
+    /// The next output sample
+    next: usize,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Self {
+        let step = sample_rate as f64 / SAMPLE_RATE as f64;
+        // Cutoff relative to the input's Nyquist frequency, below the output's when downsampling
+        let cutoff = RESAMPLE_CUTOFF * step.recip().min(1.0);
+        let half_width = RESAMPLE_TAPS / cutoff;
+        let reach = half_width.ceil() as usize;
+        let kernels = if sample_rate == SAMPLE_RATE {
+            Vec::new()
+        } else {
+            (0..RESAMPLE_PHASES)
+                .map(|phase| {
+                    let frac = phase as f64 / RESAMPLE_PHASES as f64;
+                    (0..=2 * reach)
+                        .map(|k| {
+                            let t = k as f64 - reach as f64 - frac;
+                            if t.abs() >= half_width {
+                                return 0.0;
+                            }
+                            let window = 0.5 + 0.5 * (PI * t / half_width).cos();
+                            (cutoff * sinc(cutoff * t) * window) as f32
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        Self {
+            step,
+            reach,
+            kernels,
+            input: Vec::new(),
+            start: 0,
+            next: 0,
+        }
+    }
+
+    /// Resamples as much of the input so far as doesn't need what comes after it
+    pub fn push(&mut self, samples: &[f32], output: &mut Vec<f32>) {
+        if self.kernels.is_empty() {
+            output.extend_from_slice(samples);
+            return;
+        }
+        self.input.extend_from_slice(samples);
+        let available = self.start + self.input.len();
+        while self.center(self.next).0 + self.reach < available {
+            output.push(self.sample(self.next));
+            self.next += 1;
+        }
+        let first_needed = (self.center(self.next).0.saturating_sub(self.reach)).min(available);
+        self.input.drain(..first_needed - self.start);
+        self.start = first_needed;
+    }
+
+    /// Resamples the rest of the input, as if silence followed it
+    pub fn finish(mut self, output: &mut Vec<f32>) {
+        if self.kernels.is_empty() {
+            return;
+        }
+        let len = ((self.start + self.input.len()) as f64 / self.step) as usize;
+        while self.next < len {
+            output.push(self.sample(self.next));
+            self.next += 1;
+        }
+    }
+
+    /// The input sample output sample `i` is centred on, and the kernel for the rest of its
+    /// position
+    fn center(&self, i: usize) -> (usize, usize) {
+        let position = i as f64 * self.step;
+        let phase = (position.fract() * RESAMPLE_PHASES as f64).round() as usize;
+        (
+            position as usize + phase / RESAMPLE_PHASES,
+            phase % RESAMPLE_PHASES,
+        )
+    }
+
+    fn sample(&self, i: usize) -> f32 {
+        let (center, phase) = self.center(i);
+        let kernel = &self.kernels[phase];
+        // The kernel's first tap is `reach` samples before the center
+        let skip = self.reach.saturating_sub(center);
+        let first = (center + skip) - self.reach;
+        self.input[first - self.start..]
+            .iter()
+            .zip(&kernel[skip..])
+            .map(|(sample, weight)| sample * weight)
+            .sum()
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// The energy of each note in every frame
+fn chroma(samples: &[f32]) -> Vec<[f64; BANDS]> {
+    let index = |freq: f64| (FRAME_SIZE as f64 * freq / SAMPLE_RATE as f64).round() as usize;
+    let (min_index, max_index) = (index(MIN_FREQ).max(1), index(MAX_FREQ).min(FRAME_SIZE / 2));
+    let notes: Vec<usize> = (min_index..max_index)
+        .map(|i| {
+            let freq = i as f64 * SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+            let octave = (freq / REFERENCE_FREQ).log2();
+            (BANDS as f64 * octave.fract()) as usize
+        })
+        .collect();
+    let window: Vec<f64> = (0..FRAME_SIZE)
+        .map(|i| 0.54 - 0.46 * (2.0 * PI * i as f64 / (FRAME_SIZE - 1) as f64).cos())
+        .collect();
+    let fft = Fft::new(FRAME_SIZE);
+
+    let (mut re, mut im) = (vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]);
+    let frames = (samples.len() + FRAME_STEP).saturating_sub(FRAME_SIZE) / FRAME_STEP;
+    (0..frames)
+        .map(|frame| {
+            let samples = &samples[frame * FRAME_STEP..][..FRAME_SIZE];
+            for ((re, im), (&sample, window)) in
+                re.iter_mut().zip(&mut im).zip(samples.iter().zip(&window))
+            {
+                *re = sample as f64 * window;
+                *im = 0.0;
+            }
+            fft.transform(&mut re, &mut im);
+            let mut row = [0.0; BANDS];
+            for (i, &note) in (min_index..max_index).zip(&notes) {
+                row[note] += re[i] * re[i] + im[i] * im[i];
+            }
+            row
+        })
+        .collect()
+}
+
+fn normalize(row: &mut [f64; BANDS]) {
+    let norm = row.iter().map(|value| value * value).sum::<f64>().sqrt();
+    for value in row {
+        *value = if norm < NORM_THRESHOLD {
+            0.0
+        } else {
+            *value / norm
+        };
+    }
+}
+
+/// An in-place radix 2 FFT
+struct Fft {
+    /// `e^(-2πik/n)` for the first half of `k`
+    twiddles: Vec<(f64, f64)>,
+}
+
+impl Fft {
+    fn new(len: usize) -> Self {
+        let twiddles = (0..len / 2)
+            .map(|k| {
+                let (sin, cos) = (-2.0 * PI * k as f64 / len as f64).sin_cos();
+                (cos, sin)
+            })
+            .collect();
+        Self { twiddles }
+    }
+
+    fn transform(&self, re: &mut [f64], im: &mut [f64]) {
+        let len = re.len();
+        let mut j = 0;
+        for i in 1..len {
+            let mut bit = len >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+
+        let mut size = 2;
+        while size <= len {
+            let stride = len / size;
+            for start in (0..len).step_by(size) {
+                for k in 0..size / 2 {
+                    let (cos, sin) = self.twiddles[k * stride];
+                    let (a, b) = (start + k, start + k + size / 2);
+                    let t_re = re[b] * cos - im[b] * sin;
+                    let t_im = re[b] * sin + im[b] * cos;
+                    re[b] = re[a] - t_re;
+                    im[b] = im[a] - t_im;
+                    re[a] += t_re;
+                    im[a] += t_im;
+                }
+            }
+            size <<= 1;
+        }
+    }
+}
+
+/// Sums of every rectangle from the first frame and band, so any area is four lookups
+struct IntegralImage {
+    rows: Vec<[f64; BANDS]>,
+}
+
+impl IntegralImage {
+    fn new(chroma: &[[f64; BANDS]]) -> Self {
+        let mut rows: Vec<[f64; BANDS]> = Vec::with_capacity(chroma.len());
+        for frame in chroma {
+            let mut row = [0.0; BANDS];
+            let mut band_sum = 0.0;
+            for (band, value) in frame.iter().enumerate() {
+                band_sum += value;
+                row[band] = band_sum + rows.last().map_or(0.0, |previous| previous[band]);
+            }
+            rows.push(row);
+        }
+        Self { rows }
+    }
+
+    /// The sum over frames `frame1..frame2` and bands `band1..band2`
+    fn area(&self, frame1: usize, band1: usize, frame2: usize, band2: usize) -> f64 {
+        if frame1 == frame2 || band1 == band2 {
+            return 0.0;
+        }
+        let at = |frame: usize, band: usize| {
+            if frame == 0 || band == 0 {
+                0.0
+            } else {
+                self.rows[frame - 1][band - 1]
+            }
+        };
+        at(frame2, band2) - at(frame1, band2) - at(frame2, band1) + at(frame1, band1)
+    }
+}
+
+impl Classifier {
+    fn classify(&self, image: &IntegralImage, x: usize) -> u32 {
+        let (y, w, h) = (self.band, self.frames, self.bands);
+        let area = |x1, y1, x2, y2| image.area(x1, y1, x2, y2);
+        let (a, b) = match self.kind {
+            FilterKind::Whole => (area(x, y, x + w, y + h), 0.0),
+            FilterKind::Bands2 => {
+                let h2 = h / 2;
+                (area(x, y + h2, x + w, y + h), area(x, y, x + w, y + h2))
+            }
+            FilterKind::Frames2 => {
+                let w2 = w / 2;
+                (area(x + w2, y, x + w, y + h), area(x, y, x + w2, y + h))
+            }
+            FilterKind::Quarters => {
+                let (w2, h2) = (w / 2, h / 2);
+                (
+                    area(x, y + h2, x + w2, y + h) + area(x + w2, y, x + w, y + h2),
+                    area(x, y, x + w2, y + h2) + area(x + w2, y + h2, x + w, y + h),
+                )
+            }
+            FilterKind::Bands3 => {
+                let h3 = h / 3;
+                (
+                    area(x, y + h3, x + w, y + 2 * h3),
+                    area(x, y, x + w, y + h3) + area(x, y + 2 * h3, x + w, y + h),
+                )
+            }
+            FilterKind::Frames3 => {
+                let w3 = w / 3;
+                (
+                    area(x + w3, y, x + 2 * w3, y + h),
+                    area(x, y, x + w3, y + h) + area(x + 2 * w3, y, x + w, y + h),
+                )
+            }
+        };
+        let value = (1.0 + a).ln() - (1.0 + b).ln();
+        let [t0, t1, t2] = self.thresholds;
+        match value {
+            value if value < t0 => 0,
+            value if value < t1 => 1,
+            value if value < t2 => 2,
+            _ => 3,
+        }
+    }
+}
+
+fn gray_code(value: u32) -> u32 {
+    [0, 1, 3, 2][value as usize]
+}
+
+/// Compresses a fingerprint the way Chromaprint does, into the URL-safe base64 AcoustID takes.
+/// Each subfingerprint is XORed with the one before and written as the gaps between its set
+/// bits, three bits per gap with the larger ones continued in a second five bit stream.
+pub fn encode(fingerprint: &[u32]) -> String {
+    base64_url(&compress(fingerprint))
+}
+
+fn compress(fingerprint: &[u32]) -> Vec<u8> {
+    let mut gaps = Vec::new();
+    let mut previous = 0;
+    for &subfingerprint in fingerprint {
+        let mut bits = subfingerprint ^ previous;
+        let mut last_bit = 0;
+        let mut bit = 1;
+        while bits != 0 {
+            if bits & 1 != 0 {
+                gaps.push(bit - last_bit);
+                last_bit = bit;
+            }
+            bits >>= 1;
+            bit += 1;
+        }
+        gaps.push(0);
+        previous = subfingerprint;
+    }
+
+    let len = fingerprint.len() as u32;
+    let mut bytes = vec![ALGORITHM, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    let mut normal = BitWriter::default();
+    let mut exceptional = BitWriter::default();
+    for &gap in &gaps {
+        normal.write(gap.min(7), 3);
+        if gap >= 7 {
+            exceptional.write(gap - 7, 5);
+        }
+    }
+    bytes.extend(normal.finish());
+    bytes.extend(exceptional.finish());
+    bytes
+}
+
+/// Packs values least significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u32,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.pending |= value << self.pending_bits;
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            self.bytes.push(self.pending as u8);
+            self.pending >>= 8;
+            self.pending_bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.pending_bits > 0 {
+            self.bytes.push(self.pending as u8);
+        }
+        self.bytes
+    }
+}
+
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fft_matches_dft() {
+        const LEN: usize = 64;
+        let signal: Vec<f64> = (0..LEN)
+            .map(|i| (i as f64 * 0.3).sin() + 0.5 * (i as f64 * 1.7).cos() + (i % 5) as f64)
+            .collect();
+        let (mut re, mut im) = (signal.clone(), vec![0.0; LEN]);
+        Fft::new(LEN).transform(&mut re, &mut im);
+        for k in 0..LEN {
+            let (mut dft_re, mut dft_im) = (0.0, 0.0);
+            for (n, sample) in signal.iter().enumerate() {
+                let (sin, cos) = (-2.0 * PI * (k * n) as f64 / LEN as f64).sin_cos();
+                dft_re += sample * cos;
+                dft_im += sample * sin;
+            }
+            assert!((re[k] - dft_re).abs() < 1e-9, "bin {}", k);
+            assert!((im[k] - dft_im).abs() < 1e-9, "bin {}", k);
+        }
+    }
+
+    /// `fpcalc -raw` prints this subfingerprint for every frame of digital silence, AcoustID
+    /// looks for it to tell silent tracks apart
+    #[test]
+    fn silence_matches_fpcalc() {
+        const SILENCE: u32 = 627964279;
+        for sample_rate in [SAMPLE_RATE, 44100] {
+            let fingerprint = fingerprint(&vec![0.0; sample_rate as usize * 10], sample_rate);
+            assert!(!fingerprint.is_empty());
+            assert!(
+                fingerprint.iter().all(|&sub| sub == SILENCE),
+                "{} Hz: {:?}",
+                sample_rate,
+                fingerprint
+            );
+        }
+    }
+
+    #[test]
+    fn resamples_in_blocks() {
+        for sample_rate in [8000, 44100, 48000] {
+            let signal: Vec<f32> = (0..sample_rate as usize)
+                .map(|i| (2.0 * PI * 440.0 * i as f64 / sample_rate as f64).sin() as f32)
+                .collect();
+            let whole = resample(&signal, sample_rate);
+            assert_eq!(
+                whole.len(),
+                (signal.len() as f64 / (sample_rate as f64 / SAMPLE_RATE as f64)) as usize
+            );
+            // A tone well below the cutoff comes through at the same level
+            let peak = whole[1000..10000]
+                .iter()
+                .fold(0.0f32, |peak, s| peak.max(s.abs()));
+            assert!(
+                (peak - 1.0).abs() < 0.02,
+                "{} Hz peak {}",
+                sample_rate,
+                peak
+            );
+
+            for block in [1, 7, 1000, 4096] {
+                let mut resampler = Resampler::new(sample_rate);
+                let mut blocks = Vec::new();
+                for chunk in signal.chunks(block) {
+                    resampler.push(chunk, &mut blocks);
+                }
+                resampler.finish(&mut blocks);
+                assert_eq!(blocks, *whole, "{} Hz in blocks of {}", sample_rate, block);
+            }
+        }
+    }
+
+    /// Chromaprint's own compressor tests, after the algorithm byte
+    #[test]
+    fn compresses_like_chromaprint() {
+        for (fingerprint, expected) in [
+            (&[1][..], &[0, 0, 1, 1][..]),
+            (&[7], &[0, 0, 1, 73, 0]),
+            (&[1 << 6], &[0, 0, 1, 7, 0]),
+            (&[1 << 8], &[0, 0, 1, 7, 2]),
+            (&[1, 0], &[0, 0, 2, 65, 0]),
+            (&[1, 1], &[0, 0, 2, 1, 0]),
+        ] {
+            let compressed = compress(fingerprint);
+            assert_eq!(compressed[0], ALGORITHM);
+            assert_eq!(&compressed[1..], expected, "{:?}", fingerprint);
+        }
+    }
+
+    #[test]
+    fn encodes_url_safe_base64() {
+        // From Chromaprint's `chromaprint_encode_fingerprint` test, which uses algorithm 55
+        assert_eq!(base64_url(&[55, 0, 0, 2, 65, 0]), "NwAAAkEA");
+        assert_eq!(encode(&[1, 0]), "AQAAAkEA");
+        assert_eq!(base64_url(b"x"), "eA");
+        assert_eq!(base64_url(b"xx"), "eHg");
+        assert_eq!(base64_url(b"xxx"), "eHh4");
+        assert_eq!(base64_url(&[0xfb, 0xff]), "-_8");
+    }
+}
@@ -0,0 +1,397 @@
+//! CUE sheets of albums ripped to a single file, which PowerAmp plays as separate tracks while
+//! the file's own tags describe the whole album
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    open_path,
+    tags::{picard, Tag, TagKey},
+};
+
+/// How far before a track's `INDEX 01` PowerAmp's offset may fall and still select it
+const OFFSET_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// File tags that describe the whole rip rather than one track, whatever the CUE sheet has
+const TRACK_KEYS: [TagKey; 6] = [
+    TagKey::Title,
+    TagKey::TrackNumber,
+    TagKey::Isrc,
+    TagKey::RecordingMbid,
+    TagKey::TrackMbid,
+    TagKey::WorkMbid,
+];
+
+#[derive(Debug, Default)]
+pub struct CueSheet {
+    tags: Vec<Tag>,
+    performer: Option<String>,
+    tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Default)]
+struct CueTrack {
+    file: Option<String>,
+    number: u32,
+    start: Option<Duration>,
+    tags: Vec<Tag>,
+}
+
+impl CueSheet {
+    pub fn parse(text: &str) -> Self {
+        let mut sheet = Self::default();
+        let mut file = None;
+        for line in text.lines() {
+            let (command, rest) = split_word(line.trim());
+            let command = command.to_ascii_uppercase();
+            match (command.as_str(), sheet.tracks.last_mut()) {
+                ("FILE", _) => file = Some(split_word(rest).0),
+                ("TRACK", _) => sheet.tracks.push(CueTrack {
+                    file: file.clone(),
+                    number: split_word(rest).0.parse().unwrap_or_default(),
+                    ..Default::default()
+                }),
+                ("TITLE", Some(track)) => push(&mut track.tags, TagKey::Title, rest),
+                ("TITLE", None) => push(&mut sheet.tags, TagKey::Album, rest),
+                ("PERFORMER", Some(track)) => push(&mut track.tags, TagKey::Artist, rest),
+                ("PERFORMER", None) => {
+                    push(&mut sheet.tags, TagKey::AlbumArtist, rest);
+                    sheet.performer = Some(unquote(rest));
+                }
+                ("ISRC", Some(track)) => push(&mut track.tags, TagKey::Isrc, rest),
+                ("INDEX", Some(track)) => {
+                    let (index, time) = split_word(rest);
+                    if index.parse() == Ok(1) {
+                        track.start = parse_time(time);
+                    }
+                }
+                // Rippers keep genres and MBIDs in comments, under their Vorbis names
+                ("REM", track) => {
+                    let (key, value) = split_word(rest);
+                    let key = match key.to_ascii_uppercase().as_str() {
+                        "GENRE" => Some(TagKey::Genre),
+                        _ => picard::vorbis(&key),
+                    };
+                    if let Some(key) = key {
+                        let tags = match track {
+                            Some(track) => &mut track.tags,
+                            None => &mut sheet.tags,
+                        };
+                        push(tags, key, value);
+                    }
+                }
+                _ => {}
+            }
+        }
+        sheet
+    }
+
+    /// The track playing at `offset` into `file_name`, or failing that the track titled `title`.
+    /// Without a file name an offset only says which track it is if there's just the one file.
+    fn track(
+        &self,
+        file_name: Option<&str>,
+        offset: Option<Duration>,
+        title: &str,
+    ) -> Option<&CueTrack> {
+        // Multi-file sheets list tracks of other files too
+        let stem = |name: &str| {
+            Path::new(name)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_lowercase())
+        };
+        let file_stem = file_name.and_then(stem);
+        let ours = |track: &&CueTrack| track.file.as_deref().and_then(stem) == file_stem;
+        let tracks: Vec<&CueTrack> = if self.tracks.iter().any(|track| ours(&track)) {
+            self.tracks.iter().filter(ours).collect()
+        } else {
+            self.tracks.iter().collect()
+        };
+        let single_file = tracks.windows(2).all(|pair| pair[0].file == pair[1].file);
+        let offset = offset.filter(|_| file_stem.is_some() || single_file);
+
+        offset
+            .and_then(|offset| {
+                tracks
+                    .iter()
+                    .filter(|track| track.start.is_some_and(|s| s <= offset + OFFSET_TOLERANCE))
+                    .max_by_key(|track| track.start)
+            })
+            .or_else(|| {
+                tracks.iter().find(|track| {
+                    track.tags.iter().any(|tag| {
+                        tag.key == TagKey::Title && tag.value.eq_ignore_ascii_case(title)
+                    })
+                })
+            })
+            .copied()
+    }
+
+    /// The file the playing track is in, as the sheet names it
+    pub fn track_file(&self, offset: Option<Duration>, title: &str) -> Option<&str> {
+        self.track(None, offset, title)?.file.as_deref()
+    }
+
+    /// The tags of the playing track, see [`Self::track`]
+    pub fn track_tags(
+        &self,
+        file_name: Option<&str>,
+        offset: Option<Duration>,
+        title: &str,
+    ) -> Option<Vec<Tag>> {
+        let track = self.track(file_name, offset, title)?;
+        log::debug!("Playing track {} of the CUE sheet", track.number);
+
+        let mut tags: Vec<Tag> = self.tags.iter().chain(&track.tags).cloned().collect();
+        if !track.tags.iter().any(|tag| tag.key == TagKey::Artist) {
+            if let Some(performer) = &self.performer {
+                push(&mut tags, TagKey::Artist, performer);
+            }
+        }
+        if track.number > 0 {
+            push(&mut tags, TagKey::TrackNumber, &track.number.to_string());
+        }
+        Some(tags)
+    }
+}
+
+/// Replaces the file's tags with those of the playing track, if it's part of a single-file
+/// album. The CUE sheet is embedded in the file's tags, the `external` one the service resolved,
+/// or for a CUE track, one with the same name as the file next to it.
+pub fn apply(
+    tags: &mut Vec<Tag>,
+    external: Option<&str>,
+    file_path: &Path,
+    offset: Option<Duration>,
+    title: &str,
+) {
+    let external = external.map(str::to_string);
+    let embedded = tags
+        .iter()
+        .position(|tag| tag.key == TagKey::CueSheet)
+        .map(|i| tags.remove(i).value);
+    // Only a CUE track looks for a sheet of its own, playing the whole image isn't one
+    let sibling = || {
+        offset?;
+        read_cue_file(file_path.with_extension("cue").to_str()?)
+    };
+    let Some(text) = embedded.or(external).or_else(sibling) else {
+        return;
+    };
+    let file_name = file_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let Some(cue_tags) = CueSheet::parse(&text).track_tags(Some(&file_name), offset, title) else {
+        log::warn!("No track of the CUE sheet matches the one playing");
+        return;
+    };
+
+    let has_artist = cue_tags.iter().any(|tag| tag.key == TagKey::Artist);
+    tags.retain(|tag| {
+        let replaced = TRACK_KEYS.contains(&tag.key)
+            || cue_tags.iter().any(|cue_tag| cue_tag.key == tag.key)
+            || has_artist && matches!(tag.key, TagKey::Artists | TagKey::ArtistMbid);
+        !replaced
+    });
+    tags.extend(cue_tags);
+}
+
+/// The audio file of a CUE sheet PowerAmp plays itself, which the sheet names relative to where
+/// it is. `None` when it isn't there, or isn't readable as a plain path.
+pub fn audio_file(
+    sheet: &CueSheet,
+    cue_path: &Path,
+    offset: Option<Duration>,
+    title: &str,
+) -> Option<PathBuf> {
+    // Rippers on Windows write backslashes
+    let file = sheet.track_file(offset, title)?.replace('\\', "/");
+    let path = cue_path.parent()?.join(file);
+    path.is_file().then_some(path)
+}
+
+/// Reads the CUE sheet the service resolved, which has to happen exactly once when it's a
+/// descriptor
+pub fn read_external(cue_path: &str) -> Option<String> {
+    if cue_path.is_empty() {
+        return None;
+    }
+    read_cue_file(cue_path)
+}
+
+fn read_cue_file(path: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    unsafe { open_path(path) }
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .ok()?;
+    Some(decode(&bytes))
+}
+
+/// CUE sheets are usually whatever the ripper's code page was, or UTF-8 if we're lucky
+fn decode(bytes: &[u8]) -> String {
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        char::decode_utf16(bytes.chunks_exact(2).map(|b| from_bytes([b[0], b[1]])))
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    };
+    match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8_lossy(rest).into_owned(),
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        _ => match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => bytes.iter().map(|&b| b as char).collect(),
+        },
+    }
+}
+
+/// Splits off the first, possibly quoted, word of a line
+fn split_word(line: &str) -> (String, &str) {
+    let line = line.trim_start();
+    if let Some(quoted) = line.strip_prefix('"') {
+        if let Some((word, rest)) = quoted.split_once('"') {
+            return (word.to_string(), rest.trim_start());
+        }
+    }
+    match line.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word.to_string(), rest.trim_start()),
+        None => (line.to_string(), ""),
+    }
+}
+
+/// Values are quoted when they have spaces, though plenty of rippers don't bother
+fn unquote(value: &str) -> String {
+    if value.starts_with('"') {
+        split_word(value).0
+    } else {
+        value.trim().to_string()
+    }
+}
+
+fn push(tags: &mut Vec<Tag>, key: TagKey, value: &str) {
+    let value = unquote(value);
+    let value = value.trim();
+    if !value.is_empty() {
+        tags.push(Tag {
+            key,
+            value: value.to_string(),
+        });
+    }
+}
+
+/// `mm:ss:ff`, with 75 frames to the second
+fn parse_time(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    Some(Duration::from_millis(
+        (minutes * 60 + seconds) * 1000 + frames * 1000 / 75,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"PERFORMER "Artist"
+TITLE "Album"
+FILE "Album.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "First"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second"
+    PERFORMER "Guest"
+    INDEX 01 03:20:00
+"#;
+
+    fn value(tags: &[Tag], key: TagKey) -> Option<&str> {
+        tags.iter()
+            .find(|tag| tag.key == key)
+            .map(|tag| tag.value.as_str())
+    }
+
+    #[test]
+    fn picks_track_by_offset() {
+        let sheet = CueSheet::parse(SHEET);
+        let tags = sheet
+            .track_tags(Some("Album.flac"), Some(Duration::from_secs(200)), "")
+            .unwrap();
+        assert_eq!(value(&tags, TagKey::Title), Some("Second"));
+        assert_eq!(value(&tags, TagKey::Artist), Some("Guest"));
+        assert_eq!(value(&tags, TagKey::Album), Some("Album"));
+        assert_eq!(value(&tags, TagKey::TrackNumber), Some("2"));
+
+        let tags = sheet
+            .track_tags(None, Some(Duration::from_secs(10)), "")
+            .unwrap();
+        assert_eq!(value(&tags, TagKey::Title), Some("First"));
+        assert_eq!(value(&tags, TagKey::Artist), Some("Artist"));
+    }
+
+    #[test]
+    fn multi_file_sheets_need_the_file_or_title() {
+        let sheet = CueSheet::parse(
+            "FILE \"01.flac\" WAVE\n  TRACK 01 AUDIO\n    TITLE \"First\"\n    INDEX 01 00:00:00\n\
+             FILE \"02.flac\" WAVE\n  TRACK 02 AUDIO\n    TITLE \"Second\"\n    INDEX 01 00:00:00\n",
+        );
+        assert_eq!(
+            sheet.track_file(Some(Duration::ZERO), "first"),
+            Some("01.flac")
+        );
+        assert_eq!(sheet.track_file(Some(Duration::ZERO), "Unknown"), None);
+        let tags = sheet
+            .track_tags(Some("02.flac"), Some(Duration::ZERO), "")
+            .unwrap();
+        assert_eq!(value(&tags, TagKey::Title), Some("Second"));
+    }
+
+    #[test]
+    fn finds_the_played_sheets_file() {
+        let dir = std::env::temp_dir().join(format!("lbp-cue-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cue_path = dir.join("Album.cue");
+        let sheet = CueSheet::parse(SHEET);
+        assert_eq!(
+            audio_file(&sheet, &cue_path, Some(Duration::ZERO), ""),
+            None
+        );
+        std::fs::write(dir.join("Album.flac"), b"").unwrap();
+        assert_eq!(
+            audio_file(&sheet, &cue_path, Some(Duration::ZERO), ""),
+            Some(dir.join("Album.flac"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sibling_sheets_are_for_cue_tracks() {
+        let dir = std::env::temp_dir().join(format!("lbp-cue-sibling-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Album.cue"), SHEET).unwrap();
+        let file_tags = || {
+            vec![Tag {
+                key: TagKey::Title,
+                value: "Whole album".to_string(),
+            }]
+        };
+
+        let mut tags = file_tags();
+        apply(&mut tags, None, &dir.join("Album.flac"), None, "First");
+        assert_eq!(tags, file_tags());
+
+        let mut tags = file_tags();
+        apply(
+            &mut tags,
+            None,
+            &dir.join("Album.flac"),
+            Some(Duration::ZERO),
+            "",
+        );
+        assert_eq!(value(&tags, TagKey::Title), Some("First"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+//! What the on-disk caches share: entries are JSON files, keyed on the file they describe or
+//! the query that produced them, and timestamped in seconds so they can go stale.
+
+use std::{
+    fs::File,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// A version of a file on disk, which no longer matches once the file is replaced or edited
+#[derive(Serialize, Deserialize, PartialEq, Hash, Debug)]
+pub struct FileVersion {
+    pub path: String,
+    size: u64,
+    modified_ns: u64,
+}
+
+impl FileVersion {
+    /// `None` when there's no stable path or modification time to key on
+    pub fn new(file: &File, path: &Path) -> Option<Self> {
+        let path = path.to_str().filter(|path| !path.is_empty())?;
+        let metadata = file.metadata().ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            path: path.to_string(),
+            size: metadata.len(),
+            modified_ns: modified.as_nanos() as u64,
+        })
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `None` when there's no entry or it can't be read
+pub fn read_entry<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+}
+
+/// Writes an entry to `path` in `dir`, creating `dir` first. Failing only costs a lookup the
+/// next time, so it's logged as `what` and otherwise ignored.
+pub fn write_entry<T: Serialize>(dir: &Path, path: &Path, entry: &T, what: &str) {
+    let written = std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(path, serde_json::to_vec(entry).unwrap()));
+    if let Err(e) = written {
+        log::warn!("Couldn't cache {}: {}", what, e);
+    }
+}
@@ -1,33 +1,56 @@
+mod acoustid;
+mod artists;
+mod candidates;
+mod chromaprint;
+mod cue;
+mod disk_cache;
+mod mbid_lookup;
+mod mbid_redirects;
+mod metadata;
+mod metadata_cache;
+mod overrides;
+mod path_template;
+mod quarantine;
+mod rules;
+mod submission;
+mod tag_value;
+pub mod tags;
+mod validation;
+
 use std::{
     backtrace::Backtrace,
     ffi::CStr,
     fmt::Debug,
-    io::BufWriter,
     num::NonZeroU64,
     ops::Deref,
     os::fd::FromRawFd,
-    path::{Path, PathBuf},
+    path::PathBuf,
     sync::OnceLock,
     time::{Duration, Instant, SystemTime},
 };
 
-use flume::{Receiver, RecvTimeoutError, Sender};
+use acoustid::FingerprintMatch;
+use flume::{Receiver, RecvTimeoutError, Sender, WeakSender};
 use num_enum::FromPrimitive;
 use parking_lot::Mutex;
 
+use candidates::Extraction;
+use cue::CueSheet;
 use jni::{
-    objects::{GlobalRef, JClass, JObject, JString, JValueGen},
-    sys::{jbyte, jint},
-    JNIEnv,
+    objects::{GlobalRef, JClass, JObject, JObjectArray, JString, JValueGen},
+    sys::{jboolean, jbyte, jint, jstring},
+    JNIEnv, JavaVM,
 };
+use mbid_lookup::{LookupQuery, LookupResult};
+use mbid_redirects::{EntityKind, RedirectResolver};
+use metadata::{IntentMetadata, MetadataBuilder, MetadataPrecedence};
+use metadata_cache::MetadataCache;
+use overrides::OverrideStore;
+use path_template::DEFAULT_TEMPLATE;
 use regex::Regex;
-use serde::{Serialize, Serializer};
-use symphonia::core::{
-    formats::FormatOptions,
-    io::MediaSourceStream,
-    meta::{MetadataOptions, StandardTagKey, Value},
-    probe::Hint,
-};
+use rules::RuleSet;
+use serde::{Deserialize, Serialize, Serializer};
+use submission::{submission_worker, CachePaths, Submitter};
 
 #[derive(Debug)]
 struct ListenbrainzData {
@@ -35,10 +58,36 @@ struct ListenbrainzData {
     scrobble: bool,
     token: String,
     cache_path: PathBuf,
+    quarantine_path: PathBuf,
+    lookup_cache_path: PathBuf,
+    redirect_cache_path: PathBuf,
+    fingerprint_cache_path: PathBuf,
     scrobble_deadline: Instant,
     timeout: bool,
     paused: bool,
     pause_instant: Instant,
+    track_started_at: SystemTime,
+    listened_at_mode: ListenedAtMode,
+    submission_mode: SubmissionMode,
+    track_pos: Duration,
+    scrobble_threshold: Duration,
+    played: Duration,
+    resume_instant: Instant,
+    playing_now_deadline: Option<Instant>,
+    /// Counts track changes, so late MBID lookups can tell whether their track is still current
+    track_generation: u64,
+    /// Requirements waiting on the current track's MBID or fingerprint lookup
+    pending_reqs: MetadataReqFlags,
+    /// A listen that came due before the lookup its requirements wait on finished
+    held_listen: Option<HeldListen>,
+}
+
+/// A listen waiting on its track's lookup, submitted once the lookup meets its requirements
+#[derive(Debug)]
+struct HeldListen {
+    generation: u64,
+    payload: Payload,
+    reqs: MetadataReqFlags,
 }
 
 impl Default for ListenbrainzData {
@@ -48,28 +97,125 @@ impl Default for ListenbrainzData {
             scrobble: false,
             token: String::new(),
             cache_path: PathBuf::new(),
+            quarantine_path: PathBuf::new(),
+            lookup_cache_path: PathBuf::new(),
+            redirect_cache_path: PathBuf::new(),
+            fingerprint_cache_path: PathBuf::new(),
             scrobble_deadline: Instant::now(),
             timeout: false,
             paused: true,
             pause_instant: Instant::now(),
+            track_started_at: SystemTime::now(),
+            listened_at_mode: ListenedAtMode::default(),
+            submission_mode: SubmissionMode::default(),
+            track_pos: Duration::ZERO,
+            scrobble_threshold: Duration::ZERO,
+            played: Duration::ZERO,
+            resume_instant: Instant::now(),
+            playing_now_deadline: None,
+            track_generation: 0,
+            pending_reqs: MetadataReqFlags::empty(),
+            held_listen: None,
+        }
+    }
+}
+
+impl ListenbrainzData {
+    fn listened_at(&self) -> Option<NonZeroU64> {
+        let listened_at = match self.listened_at_mode {
+            ListenedAtMode::Start => self.track_started_at,
+            ListenedAtMode::Threshold => SystemTime::now(),
+        };
+        NonZeroU64::new(
+            listened_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        )
+    }
+
+    /// Calls `f` on the metadata of the track with this generation, both while it's current and
+    /// in a listen held back for it
+    fn for_each_metadata(&mut self, generation: u64, mut f: impl FnMut(&mut TrackMetadata)) {
+        if generation == self.track_generation {
+            f(&mut self.payload.track_metadata);
+        }
+        if let Some(held) = &mut self.held_listen {
+            if held.generation == generation {
+                f(&mut held.payload.track_metadata);
+            }
+        }
+    }
+
+    /// The earliest pending deadline the event loop should wake up for
+    fn next_deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let scrobble_deadline = self
+            .timeout
+            .then_some(self.scrobble_deadline)
+            .filter(|deadline| *deadline > now);
+        [scrobble_deadline, self.playing_now_deadline]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Time actually spent playing the current track, excluding pauses
+    fn played(&self) -> Duration {
+        if self.paused {
+            self.played
+        } else {
+            self.played + self.resume_instant.elapsed()
         }
     }
 }
 
-#[derive(Serialize, Debug)]
-struct ListenbrainzSingleListen<'a> {
-    listen_type: &'static str,
-    payload: [&'a Payload; 1],
+/// Which moment of a listen gets reported as `listened_at`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ListenedAtMode {
+    /// When playback of the track began
+    #[default]
+    Start,
+    /// When the scrobble threshold elapsed
+    Threshold,
 }
 
-#[derive(Serialize, Default, Debug)]
+/// When a listen gets submitted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum SubmissionMode {
+    /// As soon as the scrobble threshold elapses
+    #[default]
+    Threshold,
+    /// Once the track ends or is skipped, along with how long it was played
+    EndOfTrack,
+}
+
+impl SubmissionMode {
+    fn from_preference(value: &str) -> Self {
+        match value {
+            "end_of_track" => Self::EndOfTrack,
+            _ => Self::Threshold,
+        }
+    }
+}
+
+impl ListenedAtMode {
+    fn from_preference(value: &str) -> Self {
+        match value {
+            "threshold" => Self::Threshold,
+            _ => Self::Start,
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug, Clone)]
 struct Payload {
     #[serde(skip_serializing_if = "Option::is_none")]
     listened_at: Option<NonZeroU64>,
     track_metadata: TrackMetadata,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct TrackMetadata {
     additional_info: AdditionalInfo,
     artist_name: String,
@@ -77,7 +223,7 @@ pub struct TrackMetadata {
     release_name: String,
 }
 
-fn serialize_artist_mbids<S>(mbids: &Vec<String>, s: S) -> Result<S::Ok, S::Error>
+fn serialize_mbids<S>(mbids: &Vec<String>, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
@@ -89,21 +235,51 @@ where
     )
 }
 
-#[derive(Serialize, Debug)]
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 struct AdditionalInfo {
+    #[serde(skip_deserializing)]
     media_player: &'static str,
+    #[serde(skip_deserializing)]
     submission_client: &'static str,
+    #[serde(skip_deserializing)]
     submission_client_version: &'static str,
     #[serde(skip_serializing_if = "String::is_empty")]
     release_mbid: String,
     #[serde(
         skip_serializing_if = "Vec::is_empty",
-        serialize_with = "serialize_artist_mbids"
+        serialize_with = "serialize_mbids"
     )]
     artist_mbids: Vec<String>,
     #[serde(skip_serializing_if = "String::is_empty")]
     recording_mbid: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    release_group_mbid: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    track_mbid: String,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "serialize_mbids"
+    )]
+    work_mbids: Vec<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    tracknumber: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    discnumber: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    isrc: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    release_artist_name: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "is_zero")]
     duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    played_duration_ms: Option<u64>,
 }
 
 #[derive(Serialize, Default, Debug)]
@@ -121,21 +297,36 @@ impl Default for AdditionalInfo {
             release_mbid: String::new(),
             artist_mbids: Vec::new(),
             recording_mbid: String::new(),
+            release_group_mbid: String::new(),
+            track_mbid: String::new(),
+            work_mbids: Vec::new(),
+            tracknumber: String::new(),
+            discnumber: String::new(),
+            isrc: String::new(),
+            release_artist_name: String::new(),
+            tags: Vec::new(),
             duration_ms: 0,
+            played_duration_ms: None,
         }
     }
 }
 
 #[derive(Debug)]
 pub enum Event {
-    TrackChanged(TrackMetadata, jint, Instant, bool),
+    TrackChanged(Box<TrackMetadata>, jint, Instant, bool, MbidChecks),
+    /// The MBID lookup for the track with this generation is done
+    MbidsResolved(u64, Option<LookupResult>),
+    /// The fingerprint lookup for the track with this generation is done
+    Fingerprinted(u64, Option<FingerprintMatch>),
+    /// The track with this generation's recording and release MBIDs were merged into these
+    MbidsRedirected(u64, Option<String>, Option<String>),
     StateChanged(PowerampState),
     SetToken(String),
 }
 
 bitflags::bitflags! {
     #[repr(transparent)]
-    #[derive(Clone, Copy, PartialEq, Eq)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
     struct MetadataReqFlags: jbyte {
         const ARTIST = 1;
         const TITLE = 2;
@@ -152,6 +343,61 @@ impl std::fmt::Display for MetadataReqFlags {
     }
 }
 
+impl MetadataReqFlags {
+    const MBIDS: Self = Self::RELEASE_MBID
+        .union(Self::ARTIST_MBIDS)
+        .union(Self::RECORDING_MBID);
+    /// What a fingerprint match says about a recording
+    const FINGERPRINTED: Self = Self::ARTIST
+        .union(Self::TITLE)
+        .union(Self::ARTIST_MBIDS)
+        .union(Self::RECORDING_MBID);
+
+    /// The requirements the metadata doesn't meet
+    fn unmet(self, track_metadata: &TrackMetadata) -> Self {
+        self.iter()
+            .filter(|&req| match req {
+                Self::ARTIST => track_metadata.artist_name.is_empty(),
+                Self::TITLE => track_metadata.track_name.is_empty(),
+                Self::ALBUM => track_metadata.release_name.is_empty(),
+                Self::RELEASE_MBID => track_metadata.additional_info.release_mbid.is_empty(),
+                Self::ARTIST_MBIDS => track_metadata.additional_info.artist_mbids.is_empty(),
+                Self::RECORDING_MBID => track_metadata.additional_info.recording_mbid.is_empty(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+}
+
+/// An MBID lookup to run for the track, and the requirements that wait on it
+#[derive(Debug)]
+pub struct PendingLookup {
+    query: LookupQuery,
+    reqs: MetadataReqFlags,
+}
+
+/// Fingerprinting an untagged file to identify it, and the requirements that wait on it
+#[derive(Debug)]
+pub struct PendingFingerprint {
+    request: acoustid::Request,
+    reqs: MetadataReqFlags,
+}
+
+/// Checking the track's MBIDs for merges, `path` is only there to say which file is stale
+#[derive(Debug)]
+pub struct RedirectCheck {
+    base_url: String,
+    path: String,
+}
+
+/// Work on the track's MBIDs that happens in the background once it's playing
+#[derive(Debug, Default)]
+pub struct MbidChecks {
+    lookup: Option<PendingLookup>,
+    fingerprint: Option<PendingFingerprint>,
+    redirects: Option<RedirectCheck>,
+}
+
 #[derive(Debug, Default, FromPrimitive)]
 #[repr(i32)]
 pub enum PowerampState {
@@ -162,150 +408,197 @@ pub enum PowerampState {
     Paused = 2,
 }
 
-async fn scrobble(listen_type: &'static str, payload: &Payload, token: &str, cache_path: &Path) {
-    let send = ListenbrainzSingleListen {
-        listen_type,
-        payload: [payload],
-    };
-    #[cfg(debug_assertions)]
-    log::debug!("{}", serde_json::to_string_pretty(&send).unwrap());
-    let status = reqwest::Client::new()
-        .post("https://api.listenbrainz.org/1/submit-listens")
-        .header("Authorization", token)
-        .json(&send)
-        .send()
-        .await
-        .unwrap()
-        .status();
-    if status.is_success() {
-        import_cache(token, cache_path).await;
-        return;
-    }
-    if let Some(listened_at) = payload.listened_at {
-        serde_json::to_writer(
-            BufWriter::new(
-                std::fs::File::create(cache_path.join(format!("{}.json", listened_at))).unwrap(),
-            ),
-            &payload,
-        )
-        .unwrap();
-    }
-}
-
-async fn import_cache(token: &str, cache_path: &Path) {
-    let mut read_dir = cache_path.read_dir().unwrap();
-    let is_occupied = read_dir.next().is_some();
-    let is_one_file = read_dir.next().is_none();
-    if cache_path.exists() && is_occupied {
-        let mut request = if is_one_file {
-            br#"{"listen_type":"single","payload":["#.to_vec()
-        } else {
-            br#"{"listen_type":"import","payload":["#.to_vec()
-        };
-        for i in std::fs::read_dir(&cache_path).unwrap().map(|f| f.unwrap()) {
-            let path = i.path();
-            std::io::copy(
-                &mut std::fs::File::open(path.as_path()).unwrap(),
-                &mut request,
-            )
-            .unwrap();
-            request.push(b',');
-        }
-        request.pop();
-        request.extend_from_slice(b"]}");
-        #[cfg(debug_assertions)]
-        log::debug!("{}", unsafe { std::str::from_utf8_unchecked(&request) });
-        let status = reqwest::Client::new()
-            .post("https://api.listenbrainz.org/1/submit-listens")
-            .header("Authorization", token)
-            .header("Content-Type", "json")
-            .body(request)
-            .send()
-            .await
-            .unwrap()
-            .status();
-        if status.is_client_error() || status.is_server_error() {
-            log::debug!("Error importing {:?}", status);
-            return;
-        }
-        std::fs::read_dir(cache_path)
-            .unwrap()
-            .try_for_each(|i| std::fs::remove_file(i?.path()))
-            .unwrap();
-    }
-}
-
 macro_rules! scrobble_duration {
     ($duration:expr,$speed:expr) => {
         if $duration <= 40_000 {
-            $duration - 1_000
+            $duration.saturating_sub(1_000)
         } else {
             u64::min(240_000, $duration / 2)
         } / $speed
     };
 }
 
+/// PowerAmp's durations past this are garbage, a day long track doesn't get scrobbled anyway
+const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long a track has to stay current before it's announced as playing now
+const PLAYING_NOW_SETTLE: Duration = Duration::from_secs(2);
+
 static EVENT_LOOP_SENDER: Mutex<Option<Sender<Event>>> = Mutex::new(None);
 static UUID_REGEX: OnceLock<Regex> = OnceLock::new();
+const UUID_PATTERN: &str = "[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}";
 static JOBJECT: OnceLock<GlobalRef> = OnceLock::new();
+/// For calling back into the service from the session, which has no `JNIEnv` of its own
+static JAVA_VM: OnceLock<JavaVM> = OnceLock::new();
+static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+static METADATA_CACHE: OnceLock<MetadataCache> = OnceLock::new();
+static RULES: Mutex<RuleSet> = Mutex::new(RuleSet::new());
+static OVERRIDES: OnceLock<Mutex<OverrideStore>> = OnceLock::new();
 
-#[tokio::main(flavor = "current_thread")]
-async fn init_thread(event: Event, mut data: ListenbrainzData, rx: Receiver<Event>) {
-    import_cache(&data.token, &data.cache_path).await;
-    log::info!("Opening thread");
+/// One playback session, from the first event until PowerAmp stops. `tx` is the session's own
+/// sender for background lookups to report back on, weak so it doesn't keep `rx` open.
+async fn run_session(
+    event: Event,
+    mut data: ListenbrainzData,
+    tx: WeakSender<Event>,
+    rx: Receiver<Event>,
+) {
+    let (submitter, submissions) = Submitter::new();
+    let worker = tokio::spawn(submission_worker(
+        submissions,
+        CachePaths {
+            cache: data.cache_path.clone(),
+            quarantine: data.quarantine_path.clone(),
+        },
+    ));
+    submitter.import_cache(data.token.clone());
+    log::info!("Opening session");
 
-    handle_event(event, &mut data).await;
+    handle_event(event, &mut data, &submitter, &tx);
     'mainloop: loop {
-        let event = if data.timeout {
-            if Instant::now() >= data.scrobble_deadline {
-                log::info!("Waiting");
-                rx.recv().map_err(|e| e.into())
-            } else {
-                log::info!(
-                    "Waiting: {:?}",
-                    data.scrobble_deadline.duration_since(Instant::now())
-                );
-                rx.recv_deadline(data.scrobble_deadline)
+        let event = if let Some(deadline) = data.next_deadline() {
+            log::info!(
+                "Waiting: {:?}",
+                deadline.saturating_duration_since(Instant::now())
+            );
+            match tokio::time::timeout_at(deadline.into(), rx.recv_async()).await {
+                Ok(event) => event.map_err(|_| RecvTimeoutError::Disconnected),
+                Err(_) => Err(RecvTimeoutError::Timeout),
             }
         } else {
             log::info!("Waiting");
-            rx.recv().map_err(|e| e.into())
+            rx.recv_async()
+                .await
+                .map_err(|_| RecvTimeoutError::Disconnected)
         };
         match event {
-            Ok(event) => handle_event(event, &mut data).await,
+            Ok(event) => handle_event(event, &mut data, &submitter, &tx),
             Err(RecvTimeoutError::Timeout) => {
-                if data.scrobble {
-                    data.payload.listened_at = NonZeroU64::new(
-                        SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
+                let now = Instant::now();
+                if data.playing_now_deadline.is_some_and(|d| d <= now) {
+                    data.playing_now_deadline = None;
+                    send_playing_now(&data, &submitter);
+                }
+                if data.timeout && data.scrobble_deadline <= now {
+                    if data.scrobble {
+                        data.payload.listened_at = data.listened_at();
+                        submit_listen(&mut data, &submitter);
+                    }
+                    data.scrobble = false;
+                    data.timeout = false;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                finish_track(&mut data, &submitter);
+                if let Some(held) = data.held_listen.take() {
+                    log::info!(
+                        "Session closed before the lookup finished, dropping the listen of {:?}",
+                        held.payload.track_metadata.track_name
                     );
-                    scrobble("single", &data.payload, &data.token, &data.cache_path).await;
                 }
-                data.scrobble = false;
-                data.timeout = false;
+                break 'mainloop;
             }
-            Err(RecvTimeoutError::Disconnected) => break 'mainloop,
         }
     }
-    log::info!("Closing thread");
+    // Let the worker flush whatever is still queued
+    drop(submitter);
+    let _ = worker.await;
+    log::info!("Closing session");
+}
+
+fn send_playing_now(data: &ListenbrainzData, submitter: &Submitter) {
+    let mut payload = data.payload.clone();
+    payload.listened_at = None;
+    submitter.listen("playing_now", payload, data.token.clone());
+}
+
+/// Submits the listen held back for the current track in [`SubmissionMode::EndOfTrack`]
+fn finish_track(data: &mut ListenbrainzData, submitter: &Submitter) {
+    if data.submission_mode != SubmissionMode::EndOfTrack || !data.scrobble {
+        return;
+    }
+    data.scrobble = false;
+
+    let played = data.played();
+    if data.track_pos + played < data.scrobble_threshold {
+        log::info!("Track ended before the scrobble threshold: {:?}", played);
+        return;
+    }
+
+    data.payload
+        .track_metadata
+        .additional_info
+        .played_duration_ms = Some(played.as_millis() as u64);
+    data.payload.listened_at = data.listened_at();
+    submit_listen(data, submitter);
+}
+
+/// Submits the current track's listen, or holds it back while a lookup its requirements wait
+/// on is still running
+fn submit_listen(data: &mut ListenbrainzData, submitter: &Submitter) {
+    if data.pending_reqs.is_empty() {
+        submitter.listen("single", data.payload.clone(), data.token.clone());
+        return;
+    }
+    log::info!(
+        "Holding the listen until the lookup for required {} finishes",
+        data.pending_reqs
+    );
+    let held = HeldListen {
+        generation: data.track_generation,
+        payload: data.payload.clone(),
+        reqs: data.pending_reqs,
+    };
+    if let Some(dropped) = data.held_listen.replace(held) {
+        log::info!(
+            "Lookup never finished, dropping the listen of {:?}",
+            dropped.payload.track_metadata.track_name
+        );
+    }
 }
 
-async fn handle_event(event: Event, data: &mut ListenbrainzData) {
+fn handle_event(
+    event: Event,
+    data: &mut ListenbrainzData,
+    submitter: &Submitter,
+    tx: &WeakSender<Event>,
+) {
     match event {
-        Event::TrackChanged(metadata, pos, now, data_scrobble) => {
-            data.payload.track_metadata = metadata;
+        Event::TrackChanged(metadata, pos, now, data_scrobble, checks) => {
+            finish_track(data, submitter);
+            data.playing_now_deadline = None;
+            data.track_generation += 1;
+            data.pending_reqs = MetadataReqFlags::empty();
+            if let Some(lookup) = checks.lookup {
+                data.pending_reqs = lookup.reqs;
+                spawn_lookup(lookup.query, data, tx);
+            }
+            if let Some(fingerprint) = checks.fingerprint {
+                data.pending_reqs = fingerprint.reqs;
+                spawn_fingerprint(fingerprint.request, data, tx);
+            }
+            if let Some(redirects) = checks.redirects {
+                spawn_redirect_check(redirects, &metadata, data, tx);
+            }
+
+            data.payload.track_metadata = *metadata;
             let pos = Duration::from_secs(pos as _);
+            // `now` is when PowerAmp reported the track, `pos` is how far into it we were
+            data.track_started_at = SystemTime::now() - now.elapsed() - pos;
+            data.track_pos = pos;
+            data.played = Duration::ZERO;
+            data.resume_instant = now;
 
             data.scrobble = data_scrobble;
-            data.timeout = data.scrobble && !data.paused;
+            data.timeout =
+                data.scrobble && !data.paused && data.submission_mode == SubmissionMode::Threshold;
 
             if data.scrobble {
                 let mut scrobble_deadline = Duration::from_millis(scrobble_duration!(
                     data.payload.track_metadata.additional_info.duration_ms,
                     1
                 ));
+                data.scrobble_threshold = scrobble_deadline;
 
                 if pos < scrobble_deadline {
                     scrobble_deadline -= pos;
@@ -316,30 +609,294 @@ async fn handle_event(event: Event, data: &mut ListenbrainzData) {
 
                 data.scrobble_deadline = now + scrobble_deadline;
 
-                data.payload.listened_at = None;
-                scrobble("playing_now", &data.payload, &data.token, &data.cache_path).await;
+                // Only announce the track once it stays current for a moment,
+                // so skipping through a playlist doesn't fire a request per track
+                data.playing_now_deadline = Some(now + PLAYING_NOW_SETTLE);
             }
         }
         Event::StateChanged(state) => match state {
-            PowerampState::Paused => {
+            // PowerAmp repeats its state now and then, only a change starts or ends a pause
+            PowerampState::Paused if !data.paused => {
+                data.played = data.played();
                 data.pause_instant = Instant::now();
                 data.timeout = false;
                 data.paused = true;
             }
-            PowerampState::Playing => {
+            PowerampState::Playing if data.paused => {
                 data.scrobble_deadline = data.scrobble_deadline + data.pause_instant.elapsed();
-                data.timeout = true;
+                data.timeout = data.submission_mode == SubmissionMode::Threshold;
                 data.paused = false;
+                data.resume_instant = Instant::now();
             }
+            PowerampState::Paused | PowerampState::Playing => {}
             // Receiver will get disconnected anyway
             PowerampState::NoState | PowerampState::Stopped => {}
         },
         Event::SetToken(token) => {
             data.token = token;
         }
+        Event::MbidsRedirected(generation, recording, release) => {
+            data.for_each_metadata(generation, |track_metadata| {
+                let additional_info = &mut track_metadata.additional_info;
+                if let Some(recording) = &recording {
+                    additional_info.recording_mbid.clone_from(recording);
+                }
+                if let Some(release) = &release {
+                    additional_info.release_mbid.clone_from(release);
+                }
+            });
+        }
+        Event::MbidsResolved(generation, result) => {
+            if let Some(result) = result {
+                data.for_each_metadata(generation, |track_metadata| result.fill(track_metadata));
+            }
+            settle_pending_reqs(data, generation, "MBID lookup", submitter);
+        }
+        Event::Fingerprinted(generation, result) => {
+            if let Some(result) = result {
+                data.for_each_metadata(generation, |track_metadata| result.fill(track_metadata));
+            }
+            settle_pending_reqs(data, generation, "Fingerprint lookup", submitter);
+        }
     }
 }
 
+/// Stops scrobbling the track if a lookup it was waiting on didn't meet its requirements, and
+/// submits or drops the listen held back for it
+fn settle_pending_reqs(
+    data: &mut ListenbrainzData,
+    generation: u64,
+    lookup: &str,
+    submitter: &Submitter,
+) {
+    if generation == data.track_generation {
+        let unmet = data.pending_reqs.unmet(&data.payload.track_metadata);
+        data.pending_reqs = MetadataReqFlags::empty();
+        if data.scrobble && !unmet.is_empty() {
+            log::info!("{} didn't find required {}, not scrobbling", lookup, unmet);
+            data.scrobble = false;
+            data.timeout = false;
+            not_scrobbling();
+        }
+    }
+    let Some(held) = data
+        .held_listen
+        .take_if(|held| held.generation == generation)
+    else {
+        return;
+    };
+    let unmet = held.reqs.unmet(&held.payload.track_metadata);
+    if unmet.is_empty() {
+        log::info!("{} finished, submitting the held listen", lookup);
+        submitter.listen("single", held.payload, data.token.clone());
+    } else {
+        log::info!(
+            "{} didn't find required {}, dropping the held listen",
+            lookup,
+            unmet
+        );
+        // Unless another track has started since, the service still says this one is scrobbled
+        if generation == data.track_generation {
+            not_scrobbling();
+        }
+    }
+}
+
+/// Tells the service the current track won't be scrobbled after all. The service was told it
+/// would be when the track started, before the lookups it was waiting on finished.
+fn not_scrobbling() {
+    let Some(vm) = JAVA_VM.get() else {
+        return;
+    };
+    let called = vm.attach_current_thread().and_then(|mut env| {
+        env.call_method(JOBJECT.get().unwrap(), "notScrobbling", "()V", &[])
+            .map(|_| ())
+    });
+    if let Err(e) = called {
+        log::error!("Couldn't tell the service the track isn't scrobbled: {}", e);
+    }
+}
+
+/// Checks the current track's MBIDs for merges in the background, the result comes back as an
+/// event
+fn spawn_redirect_check(
+    check: RedirectCheck,
+    metadata: &TrackMetadata,
+    data: &ListenbrainzData,
+    tx: &WeakSender<Event>,
+) {
+    let recording_mbid = metadata.additional_info.recording_mbid.clone();
+    let release_mbid = metadata.additional_info.release_mbid.clone();
+    if recording_mbid.is_empty() && release_mbid.is_empty() {
+        return;
+    }
+    let Some(tx) = tx.upgrade() else {
+        return;
+    };
+    let generation = data.track_generation;
+    let resolver = RedirectResolver::new(&check.base_url, data.redirect_cache_path.clone());
+    tokio::spawn(async move {
+        let mut redirected = [None, None];
+        let mbids = [
+            (EntityKind::Recording, recording_mbid),
+            (EntityKind::Release, release_mbid),
+        ];
+        for ((kind, mbid), redirected) in mbids.into_iter().zip(&mut redirected) {
+            if mbid.is_empty() {
+                continue;
+            }
+            let Some(mbid) = mbid_redirects::extract_mbid(&mbid) else {
+                continue;
+            };
+            let Some(canonical) = resolver.canonical(kind, &mbid).await else {
+                continue;
+            };
+            if canonical != mbid {
+                log::warn!(
+                    "{} carries {:?} MBID {}, which was merged into {}",
+                    check.path,
+                    kind,
+                    mbid,
+                    canonical
+                );
+                *redirected = Some(canonical);
+            }
+        }
+        let [recording, release] = redirected;
+        if recording.is_some() || release.is_some() {
+            let _ = tx.send(Event::MbidsRedirected(generation, recording, release));
+        }
+    });
+}
+
+/// Looks up the current track's MBIDs in the background, the result comes back as an event
+fn spawn_lookup(query: LookupQuery, data: &ListenbrainzData, tx: &WeakSender<Event>) {
+    let Some(tx) = tx.upgrade() else {
+        return;
+    };
+    let generation = data.track_generation;
+    let cache_path = data.lookup_cache_path.clone();
+    tokio::spawn(async move {
+        let result = mbid_lookup::lookup(query, &cache_path).await;
+        let _ = tx.send(Event::MbidsResolved(generation, result));
+    });
+}
+
+/// Identifies the current track by its audio in the background, the result comes back as an
+/// event
+fn spawn_fingerprint(request: acoustid::Request, data: &ListenbrainzData, tx: &WeakSender<Event>) {
+    let Some(tx) = tx.upgrade() else {
+        return;
+    };
+    let generation = data.track_generation;
+    let cache_path = data.fingerprint_cache_path.clone();
+    tokio::spawn(async move {
+        let result = acoustid::lookup(request, &cache_path).await;
+        let _ = tx.send(Event::Fingerprinted(generation, result));
+    });
+}
+
+fn get_preference_bool(env: &mut JNIEnv, key: &str, default: bool) -> bool {
+    let key = env.new_string(key).unwrap();
+    env.call_method(
+        JOBJECT.get().unwrap(),
+        "getPreferenceBoolean",
+        "(Ljava/lang/String;Z)Z",
+        &[key.deref().into(), default.into()],
+    )
+    .unwrap()
+    .z()
+    .unwrap()
+}
+
+fn get_preference_string(env: &mut JNIEnv, key: &str, default: &str) -> String {
+    let key = env.new_string(key).unwrap();
+    let default = env.new_string(default).unwrap();
+    let value = env
+        .call_method(
+            JOBJECT.get().unwrap(),
+            "getPreferenceString",
+            "(Ljava/lang/String;Ljava/lang/String;)Ljava/lang/String;",
+            &[key.deref().into(), default.deref().into()],
+        )
+        .unwrap();
+    let value_jstring = match value {
+        JValueGen::Object(o) => JString::from(o),
+        _ => unreachable!(),
+    };
+    let value_javastr = env.get_string(&value_jstring).unwrap();
+    let value_c_str = unsafe { CStr::from_ptr(value_javastr.as_ptr()) };
+    value_c_str.to_str().unwrap().to_string()
+}
+
+/// The real path of the file, which for a content descriptor is wherever it points to
+fn library_path(path: &str) -> PathBuf {
+    match path.strip_prefix("fd://") {
+        Some(fd) => std::fs::read_link(format!("/proc/self/fd/{}", fd)).unwrap_or_default(),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Opens a path from the service, which is either a content descriptor or a real path
+///
+/// # Safety
+///
+/// A content descriptor must be open and not owned by anything else, the file takes it over
+unsafe fn open_path(path: &str) -> std::io::Result<std::fs::File> {
+    if path.is_empty() {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no file access for this track",
+        ))
+    } else if let Some(fd) = path.strip_prefix("fd://") {
+        Ok(std::fs::File::from_raw_fd(fd.parse().unwrap()))
+    } else {
+        std::fs::File::open(path)
+    }
+}
+
+/// Reads a Java string, decoding the modified UTF-8 JNI hands over properly
+fn get_string(env: &mut JNIEnv, string: &JString) -> String {
+    env.get_string(string).map(String::from).unwrap_or_default()
+}
+
+/// One of the app's directories, from the service's `getCache` or `getFiles`
+fn app_dir(env: &mut JNIEnv, getter: &str) -> PathBuf {
+    let dir = env
+        .call_method(JOBJECT.get().unwrap(), getter, "()Ljava/lang/String;", &[])
+        .unwrap();
+    let dir_jstring = match dir {
+        JValueGen::Object(o) => JString::from(o),
+        _ => unreachable!(),
+    };
+    PathBuf::from(get_string(env, &dir_jstring))
+}
+
+/// Where listens the server rejected are kept, in the app's files
+fn quarantine_dir(env: &mut JNIEnv) -> PathBuf {
+    app_dir(env, "getFiles").join("quarantine")
+}
+
+/// The override store, loaded from the app's files on first use
+fn overrides(env: &mut JNIEnv) -> &'static Mutex<OverrideStore> {
+    OVERRIDES.get_or_init(|| {
+        Mutex::new(OverrideStore::load(
+            app_dir(env, "getFiles").join("overrides.json"),
+        ))
+    })
+}
+
+/// Reads a Java string array, skipping any nulls
+fn get_string_array(env: &mut JNIEnv, array: &JObjectArray) -> Vec<String> {
+    let len = env.get_array_length(array).unwrap();
+    (0..len)
+        .filter_map(|i| {
+            let element = env.get_object_array_element(array, i).unwrap();
+            (!element.is_null()).then(|| get_string(env, &JString::from(element)))
+        })
+        .collect()
+}
+
 fn send_event(event: Event, env: &mut JNIEnv) {
     let mut lock = EVENT_LOOP_SENDER.lock();
     if let Some(tx) = std::ops::Deref::deref(&lock) {
@@ -360,34 +917,38 @@ fn send_event(event: Event, env: &mut JNIEnv) {
         let token_javastr = env.get_string(&token_jstring).unwrap();
         let token_c_str = unsafe { CStr::from_ptr(token_javastr.as_ptr()) };
         let token = token_c_str.to_str().unwrap().to_string();
-        let cache_path = env
-            .call_method(
-                JOBJECT.get().unwrap(),
-                "getCache",
-                "()Ljava/lang/String;",
-                &[],
-            )
-            .unwrap();
-        let cache_path_jstring = match cache_path {
-            JValueGen::Object(o) => JString::from(o),
-            _ => unreachable!(),
-        };
-        let cache_path_javastr = env.get_string(&cache_path_jstring).unwrap();
-        let cache_path_c_str = unsafe { CStr::from_ptr(cache_path_javastr.as_ptr()) };
-        let cache_path = Path::new(cache_path_c_str.to_str().unwrap()).join("listenbrainz");
+        let cache_dir = app_dir(env, "getCache");
+        let cache_path = cache_dir.join("listenbrainz");
         if !cache_path.exists() {
             std::fs::create_dir(&cache_path).unwrap();
         }
+        let listened_at_mode =
+            ListenedAtMode::from_preference(&get_preference_string(env, "listened_at", "start"));
+        let submission_mode = SubmissionMode::from_preference(&get_preference_string(
+            env,
+            "submission_mode",
+            "threshold",
+        ));
         let data = ListenbrainzData {
             token,
             cache_path,
+            quarantine_path: quarantine_dir(env),
+            lookup_cache_path: cache_dir.join("mbid_lookup"),
+            redirect_cache_path: cache_dir.join("mbid_redirects"),
+            fingerprint_cache_path: cache_dir.join("acoustid"),
+            listened_at_mode,
+            submission_mode,
             ..Default::default()
         };
-        // Maximum 2 events at a time. Track, and Status
-        let (tx, rx): (Sender<Event>, Receiver<Event>) = flume::bounded(2);
+        // Unbounded so PowerAmp's broadcast thread never waits on the event loop
+        let (tx, rx): (Sender<Event>, Receiver<Event>) = flume::unbounded();
 
+        let session_tx = tx.downgrade();
         *lock = Some(tx);
-        std::thread::spawn(move || init_thread(event, data, rx));
+        RUNTIME
+            .get()
+            .unwrap()
+            .spawn(run_session(event, data, session_tx, rx));
     }
 }
 
@@ -400,6 +961,7 @@ pub extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_i
     android_logger::init_once(
         android_logger::Config::default().with_max_level(log::LevelFilter::Trace),
     );
+    let _ = JAVA_VM.set(env.get_java_vm().unwrap());
     let vm = env.get_java_vm().unwrap();
     std::panic::set_hook(Box::new(move |panic_info| {
         let thread = std::thread::current();
@@ -492,9 +1054,18 @@ pub extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_i
     */
     // log_panics::init();
     JOBJECT.set(env.new_global_ref(callback).unwrap()).unwrap();
-    UUID_REGEX
-        .set(Regex::new("[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}").unwrap())
+    RUNTIME
+        .set(
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("lbp-runtime")
+                .enable_all()
+                .build()
+                .unwrap(),
+        )
         .unwrap();
+    submission::init_client();
+    UUID_REGEX.set(Regex::new(UUID_PATTERN).unwrap()).unwrap();
 }
 
 #[no_mangle]
@@ -512,179 +1083,337 @@ pub unsafe extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundSe
     }
 }
 
+/// Adds an override given as JSON, returning why it couldn't be or an empty string
+#[no_mangle]
+pub extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_addOverride(
+    mut env: JNIEnv,
+    _: JClass,
+    json: JString,
+) -> jstring {
+    let json = get_string(&mut env, &json);
+    let error = match overrides(&mut env).lock().add(&json) {
+        Ok(()) => String::new(),
+        Err(e) => {
+            log::error!("{}", e);
+            e.to_string()
+        }
+    };
+    env.new_string(error).unwrap().into_raw()
+}
+
+/// Every override, as a JSON array
+#[no_mangle]
+pub extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_listOverrides(
+    mut env: JNIEnv,
+    _: JClass,
+) -> jstring {
+    let list = overrides(&mut env).lock().list();
+    env.new_string(list).unwrap().into_raw()
+}
+
+/// Removes the override with the key given as JSON, returning whether there was one
+#[no_mangle]
+pub extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_removeOverride(
+    mut env: JNIEnv,
+    _: JClass,
+    key: JString,
+) -> jboolean {
+    let key = get_string(&mut env, &key);
+    match overrides(&mut env).lock().remove(&key) {
+        Ok(removed) => removed as jboolean,
+        Err(e) => {
+            log::error!("{}", e);
+            0
+        }
+    }
+}
+
+/// Every listen the server rejected, as a JSON array of `{"id", "error", "quarantined_at",
+/// "listen"}`
+#[no_mangle]
+pub extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_listQuarantined(
+    mut env: JNIEnv,
+    _: JClass,
+) -> jstring {
+    let list = quarantine::list(&quarantine_dir(&mut env));
+    env.new_string(list).unwrap().into_raw()
+}
+
+/// Discards the rejected listen with this id, returning whether there was one
+#[no_mangle]
+pub extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_removeQuarantined(
+    mut env: JNIEnv,
+    _: JClass,
+    id: JString,
+) -> jboolean {
+    let id = get_string(&mut env, &id);
+    quarantine::remove(&quarantine_dir(&mut env), &id) as jboolean
+}
+
+/// Replaces the rewrite rules, returning why they're invalid or an empty string. Invalid rules
+/// leave the previous ones in place.
+#[no_mangle]
+pub extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_setRules(
+    mut env: JNIEnv,
+    _: JClass,
+    json: JString,
+) -> jstring {
+    let error = match RuleSet::parse(&get_string(&mut env, &json)) {
+        Ok(rules) => {
+            log::info!("Loaded {} rewrite rules", rules.len());
+            *RULES.lock() = rules;
+            String::new()
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            e.to_string()
+        }
+    };
+    env.new_string(error).unwrap().into_raw()
+}
+
 #[no_mangle]
 pub unsafe extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundService_mTrackFunction(
     mut env: JNIEnv,
     _: JClass,
-    path: JString,
+    paths: JObjectArray,
     ext: JString,
+    title: JString,
+    artist: JString,
+    album: JString,
+    cue_path: JString,
+    cue_offset: jint,
     dur: jint,
     pos: jint,
     metadata_reqs: jbyte,
 ) {
     let now = Instant::now();
 
-    let mut track_metadata = TrackMetadata::default();
-    track_metadata.additional_info.duration_ms = dur as u64;
+    // The track's path under every library root the service could resolve it in, none means
+    // PowerAmp's metadata is all there is
+    let mut paths = get_string_array(&mut env, &paths);
+    log::debug!("Paths: {:?}", paths);
+    let mut ext = get_string(&mut env, &ext);
+    log::debug!("Extension: {}", ext);
 
-    let path_java_str = env.get_string(&path).unwrap();
-    let path_c_str = CStr::from_ptr(path_java_str.as_ptr());
-    let path_rust = path_c_str.to_str().unwrap();
-    log::debug!("Path: {}", path_rust);
+    let intent_metadata = IntentMetadata {
+        title: get_string(&mut env, &title),
+        artist: get_string(&mut env, &artist),
+        album: get_string(&mut env, &album),
+    };
+    log::debug!("{:?}", intent_metadata);
 
-    let file = if path_rust.starts_with("fd://") {
-        Ok(std::fs::File::from_raw_fd(path_rust[5..].parse().unwrap()))
+    // Read before extraction takes the descriptors over, overrides by path still apply when the
+    // file can't be read
+    let cue_path = get_string(&mut env, &cue_path);
+    // PowerAmp plays a CUE sheet itself, the sheet says which file the track is in
+    let cue_played = ext.eq_ignore_ascii_case("cue");
+    let fallback_path = if cue_played {
+        library_path(&cue_path)
     } else {
-        std::fs::File::open(path_rust)
+        paths
+            .first()
+            .map(|path| library_path(path))
+            .unwrap_or_default()
+    };
+    let cue_sheet = cue::read_external(&cue_path);
+    let cue_offset = u64::try_from(cue_offset).ok().map(Duration::from_millis);
+    let played_sheet = cue_sheet
+        .as_deref()
+        .filter(|_| cue_played)
+        .map(CueSheet::parse);
+    if let Some(sheet) = &played_sheet {
+        match cue::audio_file(sheet, &fallback_path, cue_offset, &intent_metadata.title) {
+            Some(audio_path) => {
+                log::debug!("CUE sheet plays {}", audio_path.display());
+                ext = audio_path
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                paths = vec![audio_path.to_string_lossy().into_owned()];
+            }
+            None => log::info!("Couldn't open the CUE sheet's file, using the sheet alone"),
+        }
+    }
+    let template = get_preference_string(&mut env, "path_template", DEFAULT_TEMPLATE);
+    // PowerAmp reports -1 when it doesn't know
+    let reported_duration = u64::try_from(dur)
+        .ok()
+        .map(Duration::from_millis)
+        .filter(|duration| !duration.is_zero() && *duration <= MAX_DURATION);
+    let extraction = Extraction {
+        ext: &ext,
+        cue_sheet: cue_sheet.as_deref(),
+        cue_offset,
+        template: &template,
+        title: &intent_metadata.title,
+        reported_duration,
+        cache: METADATA_CACHE
+            .get_or_init(|| MetadataCache::new(app_dir(&mut env, "getCache").join("metadata"))),
+        compare_durations: paths.len() > 1,
     };
 
-    // Open the media source.
-    match file {
-        Ok(src) => {
-            // Create the media source stream.
-            let mss = MediaSourceStream::new(Box::new(src), Default::default());
-
-            // Create a probe hint using the file's extension. [Optional]
-            let mut hint = Hint::new();
-            let ext_java_str = env.get_string(&ext).unwrap();
-            let ext_c_str = CStr::from_ptr(ext_java_str.as_ptr());
-            let ext_rust = ext_c_str.to_str().unwrap();
-            log::debug!("Extension: {}", ext_rust);
-            hint.with_extension(ext_rust);
-
-            // Use the default options for metadata and format readers.
-            let meta_opts: MetadataOptions = Default::default();
-            let fmt_opts: FormatOptions = Default::default();
-
-            // Probe the media source.
-            let probed = symphonia::default::get_probe()
-                .format(&hint, mss, &fmt_opts, &meta_opts)
-                .expect("unsupported format");
-
-            let mut probed_metadata_vec = Vec::new();
-            let mut metadata_vec = Vec::new();
-
-            let mut metadata = probed.metadata;
-            let mut format = probed.format;
-
-            if let Some(mut m) = metadata.get() {
-                if let Some(latest) = m.skip_to_latest() {
-                    std::mem::swap(&mut latest.tags, &mut probed_metadata_vec);
+    let candidates: Vec<_> = paths
+        .iter()
+        .filter_map(|path| {
+            extraction
+                .extract(path)
+                .inspect_err(|e| log::warn!("{}: {}", path, e))
+                .ok()
+        })
+        .collect();
+    let resolved = !candidates.is_empty();
+    let sheet_tags = played_sheet
+        .filter(|_| !resolved)
+        .and_then(|sheet| sheet.track_tags(None, cue_offset, &intent_metadata.title));
+    let mut candidate_path = PathBuf::new();
+    let mut untagged_file = None;
+    let mut fallback = false;
+    let mut track_metadata =
+        match candidates::pick(candidates, &intent_metadata.title, reported_duration) {
+            Some(candidate) => {
+                log::debug!("Playing {}", candidate.path);
+                candidate_path.clone_from(&candidate.library_path);
+                // A CUE track is only part of its file, fingerprinting would need to seek
+                if candidate.untagged && extraction.cue_offset.is_none() {
+                    untagged_file = Some((candidate.file, candidate.library_path));
                 }
+                candidate.track_metadata
             }
-
-            let mut metadata = format.metadata();
-
-            if let Some(latest) = metadata.skip_to_latest() {
-                std::mem::swap(&mut latest.tags, &mut metadata_vec);
+            None if resolved => {
+                log::error!("None of the files match the playing track");
+                env.call_method(JOBJECT.get().unwrap(), "notScrobbling", "()V", &[])
+                    .unwrap();
+                return;
             }
-
-            for tag in probed_metadata_vec.drain(..).chain(metadata_vec.drain(..)) {
-                match tag.std_key {
-                    Some(StandardTagKey::Artist) => {
-                        track_metadata.artist_name = {
-                            let Value::String(tag) = tag.value else {
-                                unreachable!()
-                            };
-
-                            tag
-                        }
-                    }
-                    Some(StandardTagKey::TrackTitle) => {
-                        track_metadata.track_name = {
-                            let Value::String(tag) = tag.value else {
-                                unreachable!()
-                            };
-
-                            tag
-                        }
-                    }
-                    Some(StandardTagKey::Album) => {
-                        track_metadata.release_name = {
-                            let Value::String(tag) = tag.value else {
-                                unreachable!()
-                            };
-
-                            tag
-                        }
-                    }
-                    Some(StandardTagKey::MusicBrainzAlbumId) => {
-                        track_metadata.additional_info.release_mbid = {
-                            let Value::String(tag) = tag.value else {
-                                unreachable!()
-                            };
-
-                            tag
-                        }
-                    }
-                    Some(StandardTagKey::MusicBrainzArtistId) => {
-                        track_metadata.additional_info.artist_mbids.push({
-                            let Value::String(tag) = tag.value else {
-                                unreachable!()
-                            };
-
-                            tag
-                        })
-                    }
-                    Some(StandardTagKey::MusicBrainzRecordingId) => {
-                        track_metadata.additional_info.recording_mbid = match tag.value {
-                            Value::String(tag) => tag,
-                            Value::Binary(tag) => String::from_utf8(Vec::from(tag)).unwrap(),
-                            _ => unreachable!(),
-                        };
-                    }
-                    _ => {}
+            None if sheet_tags.is_some() => {
+                fallback = true;
+                let mut builder = MetadataBuilder::new(
+                    reported_duration.map_or(0, |duration| duration.as_millis() as u64),
+                );
+                for tag in sheet_tags.unwrap() {
+                    builder.add(tag);
                 }
+                builder.finish()
             }
-
-            log::debug!("{:#?}", track_metadata);
-            let metadata_reqs = MetadataReqFlags::from_bits(metadata_reqs).unwrap();
-            log::debug!("Reqs: {}", metadata_reqs);
-            let mut scrobble = true;
-            for req in metadata_reqs {
-                match req {
-                    MetadataReqFlags::ARTIST => {
-                        scrobble = scrobble && !track_metadata.artist_name.is_empty()
-                    }
-                    MetadataReqFlags::TITLE => {
-                        scrobble = scrobble && !track_metadata.track_name.is_empty()
-                    }
-                    MetadataReqFlags::ALBUM => {
-                        scrobble = scrobble && !track_metadata.release_name.is_empty()
-                    }
-                    MetadataReqFlags::RELEASE_MBID => {
-                        scrobble =
-                            scrobble && !track_metadata.additional_info.release_mbid.is_empty()
-                    }
-                    MetadataReqFlags::ARTIST_MBIDS => {
-                        scrobble =
-                            scrobble && !track_metadata.additional_info.artist_mbids.is_empty()
-                    }
-                    MetadataReqFlags::RECORDING_MBID => {
-                        scrobble =
-                            scrobble && !track_metadata.additional_info.recording_mbid.is_empty()
-                    }
-                    _ => unreachable!(),
-                }
+            None if !intent_metadata.is_empty() => {
+                log::warn!("No file access for this track, using PowerAmp's metadata");
+                fallback = true;
+                MetadataBuilder::new(
+                    reported_duration.map_or(0, |duration| duration.as_millis() as u64),
+                )
+                .finish()
             }
-            if scrobble {
-                env.call_method(JOBJECT.get().unwrap(), "isScrobbling", "()V", &[])
-                    .unwrap();
-            } else {
+            None => {
+                log::error!("No file access for this track and no metadata from PowerAmp");
                 env.call_method(JOBJECT.get().unwrap(), "notScrobbling", "()V", &[])
                     .unwrap();
+                return;
+            }
+        };
+    let precedence = MetadataPrecedence::from_preference(&get_preference_string(
+        &mut env,
+        "metadata_precedence",
+        "tags",
+    ));
+    intent_metadata.merge_into(&mut track_metadata, precedence);
+    // After PowerAmp's metadata, an override is the user's word on the track
+    let override_path = if fallback {
+        &fallback_path
+    } else {
+        &candidate_path
+    };
+    overrides(&mut env)
+        .lock()
+        .apply(override_path, &mut track_metadata);
+    RULES.lock().apply(&mut track_metadata);
+
+    log::debug!("{:#?}", track_metadata);
+    let metadata_reqs = MetadataReqFlags::from_bits(metadata_reqs).unwrap();
+    log::debug!("Reqs: {}", metadata_reqs);
+    let unmet = metadata_reqs.unmet(&track_metadata);
+    let duration = Duration::from_millis(track_metadata.additional_info.duration_ms);
+    let fingerprint = match untagged_file {
+        Some((file, library_path))
+            if !duration.is_zero()
+                && track_metadata.additional_info.recording_mbid.is_empty()
+                && get_preference_bool(&mut env, "acoustid_lookup", false) =>
+        {
+            let api_key = get_preference_string(&mut env, "acoustid_key", "");
+            let url = get_preference_string(&mut env, "acoustid_url", acoustid::DEFAULT_URL);
+            if api_key.is_empty() {
+                log::warn!("Fingerprint lookups need an AcoustID API key");
+                None
+            } else {
+                Some(PendingFingerprint {
+                    request: acoustid::Request::new(
+                        file,
+                        &library_path,
+                        &ext,
+                        duration,
+                        &url,
+                        &api_key,
+                    ),
+                    reqs: unmet & MetadataReqFlags::FINGERPRINTED,
+                })
             }
-            send_event(
-                Event::TrackChanged(track_metadata, pos, now, scrobble),
-                &mut env,
-            );
-        }
-        Err(e) => {
-            log::error!("{:#?}", e);
-            env.call_method(JOBJECT.get().unwrap(), "notScrobbling", "()V", &[])
-                .unwrap();
         }
+        _ => None,
+    };
+    // The audio says more than names guessed from the path
+    let lookup = if fingerprint.is_none() && get_preference_bool(&mut env, "mbid_lookup", false) {
+        LookupQuery::for_track(&track_metadata).map(|query| PendingLookup {
+            query,
+            // MBIDs the lookup may yet fill in don't count against the track until it's done
+            reqs: unmet & MetadataReqFlags::MBIDS,
+        })
+    } else {
+        None
+    };
+    let deferred = lookup
+        .as_ref()
+        .map_or(MetadataReqFlags::empty(), |lookup| lookup.reqs)
+        | fingerprint
+            .as_ref()
+            .map_or(MetadataReqFlags::empty(), |fingerprint| fingerprint.reqs);
+    let redirects = get_preference_bool(&mut env, "mbid_redirects", false).then(|| RedirectCheck {
+        base_url: get_preference_string(
+            &mut env,
+            "musicbrainz_url",
+            mbid_redirects::DEFAULT_BASE_URL,
+        ),
+        path: candidate_path.display().to_string(),
+    });
+    // Without a duration there's no telling when the track counts as listened to
+    let mut scrobble = track_metadata.additional_info.duration_ms > 0;
+    if !scrobble {
+        log::warn!("No duration for this track, not scrobbling it");
     }
+    if !(unmet - deferred).is_empty() {
+        log::info!("Missing required metadata: {}", unmet - deferred);
+        scrobble = false;
+    }
+    if scrobble {
+        env.call_method(JOBJECT.get().unwrap(), "isScrobbling", "()V", &[])
+            .unwrap();
+    } else {
+        env.call_method(JOBJECT.get().unwrap(), "notScrobbling", "()V", &[])
+            .unwrap();
+    }
+    send_event(
+        Event::TrackChanged(
+            Box::new(track_metadata),
+            pos,
+            now,
+            scrobble,
+            MbidChecks {
+                lookup,
+                fingerprint,
+                redirects,
+            },
+        ),
+        &mut env,
+    );
 }
 
 #[no_mangle]
@@ -707,3 +1436,35 @@ pub unsafe extern "system" fn Java_com_example_listenbrainzpoweramp_ForegroundSe
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_states_keep_the_played_time() {
+        let (submitter, _submissions) = Submitter::new();
+        let (tx, _rx) = flume::unbounded();
+        let tx = tx.downgrade();
+        let mut data = ListenbrainzData::default();
+        let state = |data: &mut ListenbrainzData, state| {
+            handle_event(Event::StateChanged(state), data, &submitter, &tx)
+        };
+        let ago = |secs| Instant::now() - Duration::from_secs(secs);
+
+        state(&mut data, PowerampState::Playing);
+        data.resume_instant = ago(10);
+        state(&mut data, PowerampState::Playing);
+        assert!(data.played() >= Duration::from_secs(10));
+
+        state(&mut data, PowerampState::Paused);
+        data.pause_instant = ago(5);
+        let deadline = data.scrobble_deadline;
+        state(&mut data, PowerampState::Paused);
+        let played = data.played();
+        assert!(played >= Duration::from_secs(10) && played < Duration::from_secs(11));
+
+        state(&mut data, PowerampState::Playing);
+        assert!(data.scrobble_deadline >= deadline + Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,167 @@
+//! Fills in the MBIDs of tracks tagged with names only, through ListenBrainz's metadata lookup.
+//! Lookups run alongside the session so they never hold up scrobble timing, and their results
+//! are kept on disk so replays don't query again.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    disk_cache::{self, now_secs},
+    submission::http_client,
+    tag_value::comparable,
+    TrackMetadata,
+};
+
+const LOOKUP_URL: &str = "https://api.listenbrainz.org/1/metadata/lookup/";
+
+/// How long a lookup that found nothing is trusted, the database keeps growing
+const NEGATIVE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LookupQuery {
+    artist_name: String,
+    recording_name: String,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    release_name: String,
+}
+
+impl LookupQuery {
+    /// `None` when the track already has its MBIDs or doesn't have the names to look them up by
+    pub fn for_track(track_metadata: &TrackMetadata) -> Option<Self> {
+        let additional_info = &track_metadata.additional_info;
+        let complete = !additional_info.recording_mbid.is_empty()
+            && !additional_info.release_mbid.is_empty()
+            && !additional_info.artist_mbids.is_empty();
+        if complete || track_metadata.artist_name.is_empty() || track_metadata.track_name.is_empty()
+        {
+            return None;
+        }
+        Some(Self {
+            artist_name: track_metadata.artist_name.clone(),
+            recording_name: track_metadata.track_name.clone(),
+            release_name: track_metadata.release_name.clone(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LookupResult {
+    pub recording_mbid: String,
+    pub release_mbid: String,
+    pub artist_mbids: Vec<String>,
+}
+
+impl LookupResult {
+    /// Fills in whichever MBIDs the track doesn't have, the tags know better
+    pub fn fill(&self, track_metadata: &mut TrackMetadata) {
+        let additional_info = &mut track_metadata.additional_info;
+        if additional_info.recording_mbid.is_empty() {
+            additional_info
+                .recording_mbid
+                .clone_from(&self.recording_mbid);
+        }
+        if additional_info.release_mbid.is_empty() {
+            additional_info.release_mbid.clone_from(&self.release_mbid);
+        }
+        if additional_info.artist_mbids.is_empty() {
+            additional_info.artist_mbids.clone_from(&self.artist_mbids);
+        }
+    }
+}
+
+/// The endpoint answers with an empty object when nothing matches
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LookupResponse {
+    artist_credit_name: String,
+    artist_mbids: Vec<String>,
+    recording_mbid: String,
+    recording_name: String,
+    release_mbid: String,
+    release_name: String,
+}
+
+impl LookupResponse {
+    /// The lookup is fuzzy and always returns its best guess, which is only taken when the
+    /// names are the same as ours but for case, spacing and punctuation
+    fn into_result(self, query: &LookupQuery) -> Option<LookupResult> {
+        let agrees = |ours: &str, theirs: &str| {
+            let ours = comparable(ours);
+            !ours.is_empty() && ours == comparable(theirs)
+        };
+        if self.recording_mbid.is_empty()
+            || !agrees(&query.recording_name, &self.recording_name)
+            || !agrees(&query.artist_name, &self.artist_credit_name)
+        {
+            return None;
+        }
+        // The same recording is on many releases, the guess is only ours if the names match
+        let release_mbid = if agrees(&query.release_name, &self.release_name) {
+            self.release_mbid
+        } else {
+            String::new()
+        };
+        Some(LookupResult {
+            recording_mbid: self.recording_mbid,
+            release_mbid,
+            artist_mbids: self.artist_mbids,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    query: LookupQuery,
+    result: Option<LookupResult>,
+    looked_up_at: u64,
+}
+
+fn cache_file(cache_dir: &Path, query: &LookupQuery) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    query.hash(&mut hasher);
+    cache_dir.join(format!("{:016x}.json", hasher.finish()))
+}
+
+fn read_cache(path: &Path, query: &LookupQuery) -> Option<Option<LookupResult>> {
+    let entry: CacheEntry = disk_cache::read_entry(path)?;
+    let stale = entry.result.is_none()
+        && now_secs().saturating_sub(entry.looked_up_at) > NEGATIVE_TTL.as_secs();
+    (entry.query == *query && !stale).then_some(entry.result)
+}
+
+/// Looks up the MBIDs of a track by name. `None` when there's no confident match or the lookup
+/// failed, failures aren't cached so the next play tries again.
+pub async fn lookup(query: LookupQuery, cache_dir: &Path) -> Option<LookupResult> {
+    let path = cache_file(cache_dir, &query);
+    if let Some(result) = read_cache(&path, &query) {
+        log::debug!("Cached MBID lookup for {:?}: {:?}", query, result);
+        return result;
+    }
+
+    let response = http_client().get(LOOKUP_URL).query(&query).send().await;
+    let response = match response.and_then(|response| response.error_for_status()) {
+        Ok(response) => response.json::<LookupResponse>().await,
+        Err(e) => Err(e),
+    };
+    let result = match response {
+        Ok(response) => response.into_result(&query),
+        Err(e) => {
+            log::warn!("MBID lookup failed: {}", e);
+            return None;
+        }
+    };
+    log::info!("MBID lookup for {:?}: {:?}", query, result);
+
+    let entry = CacheEntry {
+        query,
+        result,
+        looked_up_at: now_secs(),
+    };
+    disk_cache::write_entry(cache_dir, &path, &entry, "MBID lookup");
+    entry.result
+}
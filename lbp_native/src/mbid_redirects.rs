@@ -0,0 +1,145 @@
+//! Follows recording and release MBIDs that MusicBrainz has since merged into others, which old
+//! Picard tags are full of. MusicBrainz answers a lookup of a merged MBID with the entity it
+//! was merged into, so a lookup whose ID differs is a redirect.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    disk_cache::{self, now_secs},
+    submission::http_client,
+    UUID_REGEX,
+};
+
+pub const DEFAULT_BASE_URL: &str = "https://musicbrainz.org";
+
+/// MusicBrainz asks clients to identify themselves
+const USER_AGENT: &str = concat!(
+    "ListenBrainzPowerAmp/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/StratusFearMe21/listenbrainz-poweramp )"
+);
+
+/// MusicBrainz allows one request a second
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an MBID that wasn't merged is trusted, merges are forever but can happen any time
+const CANONICAL_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+static LAST_REQUEST: parking_lot::Mutex<Option<Instant>> = parking_lot::Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+pub enum EntityKind {
+    Recording,
+    Release,
+}
+
+impl EntityKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Recording => "recording",
+            Self::Release => "release",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    canonical: String,
+    checked_at: u64,
+}
+
+#[derive(Deserialize)]
+struct Entity {
+    id: String,
+}
+
+/// The MBID in a tag value, which can hold more than just the MBID
+pub fn extract_mbid(value: &str) -> Option<String> {
+    let value = value.to_ascii_lowercase();
+    let mbid = UUID_REGEX.get().unwrap().find(&value)?;
+    Some(mbid.as_str().to_string())
+}
+
+pub struct RedirectResolver {
+    base_url: String,
+    cache_dir: PathBuf,
+}
+
+impl RedirectResolver {
+    /// `base_url` is the MusicBrainz server, or a mirror of it
+    pub fn new(base_url: &str, cache_dir: PathBuf) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cache_dir,
+        }
+    }
+
+    fn cache_file(&self, kind: EntityKind, mbid: &str) -> PathBuf {
+        self.cache_dir
+            .join(format!("{}-{}.json", kind.as_str(), mbid))
+    }
+
+    fn read_cache(path: &Path, mbid: &str) -> Option<String> {
+        let entry: CacheEntry = disk_cache::read_entry(path)?;
+        let stale = entry.canonical == mbid
+            && now_secs().saturating_sub(entry.checked_at) > CANONICAL_TTL.as_secs();
+        (!stale).then_some(entry.canonical)
+    }
+
+    /// The MBID `mbid` was merged into, or itself if it wasn't. `None` when it couldn't be
+    /// checked, or MusicBrainz doesn't know it at all.
+    pub async fn canonical(&self, kind: EntityKind, mbid: &str) -> Option<String> {
+        let mbid = extract_mbid(mbid)?;
+        let path = self.cache_file(kind, &mbid);
+        if let Some(canonical) = Self::read_cache(&path, &mbid) {
+            return Some(canonical);
+        }
+
+        let wait = {
+            let mut last_request = LAST_REQUEST.lock();
+            let now = Instant::now();
+            let next = last_request.map_or(now, |last| (last + REQUEST_INTERVAL).max(now));
+            *last_request = Some(next);
+            next - now
+        };
+        tokio::time::sleep(wait).await;
+
+        let url = format!("{}/ws/2/{}/{}", self.base_url, kind.as_str(), mbid);
+        let response = http_client()
+            .get(&url)
+            .query(&[("fmt", "json")])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .send()
+            .await;
+        let entity = match response {
+            Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => {
+                log::warn!("MusicBrainz doesn't know {} {}", kind.as_str(), mbid);
+                return None;
+            }
+            Ok(response) => match response.error_for_status() {
+                Ok(response) => response.json::<Entity>().await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        let canonical = match entity {
+            Ok(entity) => entity.id.to_ascii_lowercase(),
+            Err(e) => {
+                log::warn!("Couldn't check {} {}: {}", kind.as_str(), mbid, e);
+                return None;
+            }
+        };
+
+        let entry = CacheEntry {
+            canonical,
+            checked_at: now_secs(),
+        };
+        disk_cache::write_entry(&self.cache_dir, &path, &entry, "MBID redirect");
+        Some(entry.canonical)
+    }
+}
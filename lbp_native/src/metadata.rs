@@ -0,0 +1,158 @@
+use crate::{
+    artists::{push_unique, ArtistCredits},
+    tag_value::clean_text,
+    tags::{Tag, TagKey},
+    TrackMetadata,
+};
+
+/// Whose metadata wins when both the file tags and PowerAmp have a value
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataPrecedence {
+    /// PowerAmp's metadata only fills in what the tags are missing
+    #[default]
+    Tags,
+    /// The tags only fill in what PowerAmp's metadata is missing
+    Intent,
+}
+
+impl MetadataPrecedence {
+    pub fn from_preference(value: &str) -> Self {
+        match value {
+            "intent" => Self::Intent,
+            _ => Self::Tags,
+        }
+    }
+}
+
+/// The metadata PowerAmp sends along with TRACK_CHANGED, which we have even when the file
+/// can't be opened
+#[derive(Debug, Default)]
+pub struct IntentMetadata {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+}
+
+impl IntentMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_empty() && self.artist.is_empty() && self.album.is_empty()
+    }
+
+    pub fn merge_into(self, track_metadata: &mut TrackMetadata, precedence: MetadataPrecedence) {
+        for (field, value) in [
+            (&mut track_metadata.track_name, self.title),
+            (&mut track_metadata.artist_name, self.artist),
+            (&mut track_metadata.release_name, self.album),
+        ] {
+            let Some(value) = clean_text(&value) else {
+                continue;
+            };
+            if field.is_empty() || precedence == MetadataPrecedence::Intent {
+                *field = value;
+            }
+        }
+    }
+}
+
+/// Turns the tags of a file into the metadata we submit, whichever reader produced them
+#[derive(Debug, Default)]
+pub struct MetadataBuilder {
+    track_metadata: TrackMetadata,
+    artist_credits: ArtistCredits,
+}
+
+impl MetadataBuilder {
+    pub fn new(duration_ms: u64) -> Self {
+        let mut builder = Self::default();
+        builder.track_metadata.additional_info.duration_ms = duration_ms;
+        builder
+    }
+
+    pub fn add(&mut self, tag: Tag) {
+        let info = &mut self.track_metadata.additional_info;
+        let value = tag.value;
+        match tag.key {
+            TagKey::Title => self.track_metadata.track_name = value,
+            TagKey::Artist => self.artist_credits.push_artist(value),
+            TagKey::Artists => self.artist_credits.push_artists(value),
+            TagKey::Album => self.track_metadata.release_name = value,
+            TagKey::AlbumArtist => info.release_artist_name = value,
+            // "3/12" style values carry the total as well
+            TagKey::TrackNumber => info.tracknumber = number_part(&value),
+            TagKey::DiscNumber => info.discnumber = number_part(&value),
+            TagKey::Isrc => info.isrc = value,
+            TagKey::Genre => {
+                for genre in value.split(';').map(str::trim) {
+                    if !genre.is_empty() {
+                        push_unique(&mut info.tags, genre.to_string());
+                    }
+                }
+            }
+            TagKey::RecordingMbid => info.recording_mbid = value,
+            TagKey::TrackMbid => info.track_mbid = value,
+            TagKey::ReleaseMbid => info.release_mbid = value,
+            // Files often carry the same tags in more than one block, so keep only the first
+            // occurrence to preserve the credit order
+            TagKey::ArtistMbid => {
+                for mbid in split_mbids(&value) {
+                    push_unique(&mut info.artist_mbids, mbid);
+                }
+            }
+            TagKey::ReleaseGroupMbid => info.release_group_mbid = value,
+            TagKey::WorkMbid => {
+                for mbid in split_mbids(&value) {
+                    push_unique(&mut info.work_mbids, mbid);
+                }
+            }
+            // Resolved to the playing track's tags before they get here
+            TagKey::CueSheet => {}
+        }
+    }
+
+    /// Adds a tag inferred from somewhere other than the file's tags, if the tags left that
+    /// field empty
+    pub fn add_inferred(&mut self, tag: Tag) {
+        let info = &self.track_metadata.additional_info;
+        let missing = match tag.key {
+            TagKey::Title => self.track_metadata.track_name.is_empty(),
+            TagKey::Artist => self.artist_credits.is_empty(),
+            TagKey::Album => self.track_metadata.release_name.is_empty(),
+            TagKey::AlbumArtist => info.release_artist_name.is_empty(),
+            TagKey::TrackNumber => info.tracknumber.is_empty(),
+            TagKey::DiscNumber => info.discnumber.is_empty(),
+            _ => false,
+        };
+        if missing {
+            log::info!("Inferred {:?} \"{}\" from the path", tag.key, tag.value);
+            self.add(tag);
+        }
+    }
+
+    pub fn finish(mut self) -> TrackMetadata {
+        self.track_metadata.artist_name = self.artist_credits.credit();
+        let artist_names = self.artist_credits.names();
+        log::debug!("Artists: {:?}", artist_names);
+        let artist_mbids = &self.track_metadata.additional_info.artist_mbids;
+        if !artist_mbids.is_empty() && artist_mbids.len() != artist_names.len() {
+            log::warn!(
+                "{} credited artists but {} artist MBIDs",
+                artist_names.len(),
+                artist_mbids.len()
+            );
+        }
+        self.track_metadata
+    }
+}
+
+/// ID3v2.3 frames can't hold several values, so Picard joins MBIDs with "/" there
+fn split_mbids(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(['/', ';'])
+        .map(str::trim)
+        .filter(|mbid| !mbid.is_empty())
+        .map(str::to_string)
+}
+
+fn number_part(value: &str) -> String {
+    value.split('/').next().unwrap().trim().to_string()
+}
@@ -0,0 +1,153 @@
+//! Persistent cache of the metadata extracted from each file, so replaying an album doesn't
+//! reopen and reprobe every track
+
+use std::{
+    fs::File,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{disk_cache::FileVersion, TrackMetadata};
+
+/// Past this many entries the least recently used quarter is dropped
+const MAX_ENTRIES: usize = 4096;
+
+/// Identifies what a file's metadata was extracted from, anything that changes the result
+/// included
+#[derive(Serialize, Deserialize, PartialEq, Hash, Debug)]
+pub struct CacheKey {
+    #[serde(flatten)]
+    file: FileVersion,
+    cue_offset: Option<Duration>,
+    /// Hash of the external CUE sheet, which can change without the file changing
+    cue_sheet: Option<u64>,
+    template: String,
+}
+
+impl CacheKey {
+    /// `None` when there's no stable path or modification time to key on
+    pub fn new(
+        file: &File,
+        path: &Path,
+        cue_offset: Option<Duration>,
+        cue_sheet: Option<&str>,
+        template: &str,
+    ) -> Option<Self> {
+        Some(Self {
+            file: FileVersion::new(file, path)?,
+            cue_offset,
+            cue_sheet: cue_sheet.map(|cue_sheet| {
+                let mut hasher = DefaultHasher::new();
+                cue_sheet.hash(&mut hasher);
+                hasher.finish()
+            }),
+            template: template.to_string(),
+        })
+    }
+
+    /// Entries are named after the track rather than its version, so a changed file
+    /// overwrites its stale entry
+    fn file_name(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        (&self.file.path, self.cue_offset).hash(&mut hasher);
+        format!("{:016x}.json", hasher.finish())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: CacheKey,
+    track_metadata: TrackMetadata,
+    untagged: bool,
+}
+
+pub struct MetadataCache {
+    dir: PathBuf,
+    /// Entries as of the last count plus those inserted since, which overcounts replaced ones
+    entries: AtomicUsize,
+}
+
+impl MetadataCache {
+    pub fn new(dir: PathBuf) -> Self {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            log::warn!("Couldn't create the metadata cache: {}", e);
+        }
+        let entries = dir.read_dir().map_or(0, |read_dir| read_dir.count());
+        Self {
+            dir,
+            entries: AtomicUsize::new(entries),
+        }
+    }
+
+    /// The file's metadata and whether it had no usable tags
+    pub fn get(&self, key: &CacheKey) -> Option<(TrackMetadata, bool)> {
+        let path = self.dir.join(key.file_name());
+        let file = File::open(&path).ok()?;
+        let entry: Entry = match serde_json::from_reader(BufReader::new(&file)) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("Dropping unreadable metadata cache entry: {}", e);
+                let _ = std::fs::remove_file(&path);
+                return None;
+            }
+        };
+        if entry.key != *key {
+            return None;
+        }
+        // The entry's modification time doubles as when it was last used
+        let _ = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        Some((entry.track_metadata, entry.untagged))
+    }
+
+    pub fn insert(&self, key: CacheKey, track_metadata: &TrackMetadata, untagged: bool) {
+        let entry = Entry {
+            key,
+            track_metadata: track_metadata.clone(),
+            untagged,
+        };
+        let result = File::create(self.dir.join(entry.key.file_name()))
+            .map_err(serde_json::Error::io)
+            .and_then(|file| serde_json::to_writer(file, &entry));
+        if let Err(e) = result {
+            log::warn!("Couldn't write metadata cache entry: {}", e);
+            return;
+        }
+        // Counting the entries means statting every one of them, so only when there may be
+        // too many
+        if self.entries.fetch_add(1, Ordering::Relaxed) >= MAX_ENTRIES {
+            self.prune();
+        }
+    }
+
+    fn prune(&self) {
+        let Ok(read_dir) = self.dir.read_dir() else {
+            return;
+        };
+        let mut entries: Vec<(SystemTime, PathBuf)> = read_dir
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                Some((entry.metadata().ok()?.modified().ok()?, entry.path()))
+            })
+            .collect();
+        if entries.len() <= MAX_ENTRIES {
+            self.entries.store(entries.len(), Ordering::Relaxed);
+            return;
+        }
+        entries.sort_unstable();
+        let excess = entries.len() - MAX_ENTRIES * 3 / 4;
+        log::info!("Pruning {} metadata cache entries", excess);
+        for (_, path) in &entries[..excess] {
+            let _ = std::fs::remove_file(path);
+        }
+        self.entries
+            .store(entries.len() - excess, Ordering::Relaxed);
+    }
+}
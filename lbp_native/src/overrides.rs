@@ -0,0 +1,345 @@
+//! Local overrides for files that can't or won't be retagged, keyed by the file's path or by the
+//! release or recording MBID its tags carry. Overrides are stored as JSON:
+//!
+//! ```json
+//! [
+//!     { "match": { "release_mbid": "…" }, "set": { "album": "…", "release_group_mbid": "…" } },
+//!     { "match": { "path": "/storage/emulated/0/Music/…/01.flac" }, "set": { "recording_mbid": "…" } }
+//! ]
+//! ```
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{mbid_redirects::extract_mbid, TrackMetadata};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideKey {
+    Path(String),
+    ReleaseMbid(String),
+    RecordingMbid(String),
+}
+
+impl OverrideKey {
+    /// The key with its MBID normalized, or `None` when it couldn't match any track
+    fn validate(self) -> Option<Self> {
+        Some(match self {
+            Self::Path(key) if key.trim().is_empty() => return None,
+            Self::Path(key) => Self::Path(key),
+            Self::ReleaseMbid(key) => Self::ReleaseMbid(extract_mbid(&key)?),
+            Self::RecordingMbid(key) => Self::RecordingMbid(extract_mbid(&key)?),
+        })
+    }
+
+    fn matches(&self, path: &Path, track_metadata: &TrackMetadata) -> bool {
+        // A track without the field matches no key, however blank
+        let same_mbid = |ours: &str, theirs: &str| {
+            !theirs.trim().is_empty() && ours.trim().eq_ignore_ascii_case(theirs.trim())
+        };
+        match self {
+            Self::Path(key) => !key.is_empty() && Path::new(key) == path,
+            Self::ReleaseMbid(key) => same_mbid(key, &track_metadata.additional_info.release_mbid),
+            Self::RecordingMbid(key) => {
+                same_mbid(key, &track_metadata.additional_info.recording_mbid)
+            }
+        }
+    }
+
+    /// Release overrides go first so the more specific ones win
+    fn specificity(&self) -> u8 {
+        match self {
+            Self::ReleaseMbid(_) => 0,
+            Self::RecordingMbid(_) => 1,
+            Self::Path(_) => 2,
+        }
+    }
+}
+
+/// Replacement values, fields left out are kept as they are
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct OverrideFields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    album_artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    artist_mbids: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recording_mbid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    release_mbid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    release_group_mbid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    track_mbid: Option<String>,
+}
+
+impl OverrideFields {
+    fn apply(&self, track_metadata: &mut TrackMetadata) {
+        let set = |field: &mut String, value: &Option<String>| {
+            if let Some(value) = value {
+                field.clone_from(value);
+            }
+        };
+        let additional_info = &mut track_metadata.additional_info;
+        set(&mut track_metadata.artist_name, &self.artist);
+        set(&mut track_metadata.track_name, &self.title);
+        set(&mut track_metadata.release_name, &self.album);
+        set(&mut additional_info.release_artist_name, &self.album_artist);
+        if let Some(artist_mbids) = &self.artist_mbids {
+            additional_info.artist_mbids.clone_from(artist_mbids);
+        }
+        set(&mut additional_info.recording_mbid, &self.recording_mbid);
+        set(&mut additional_info.release_mbid, &self.release_mbid);
+        set(
+            &mut additional_info.release_group_mbid,
+            &self.release_group_mbid,
+        );
+        set(&mut additional_info.track_mbid, &self.track_mbid);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Override {
+    #[serde(rename = "match")]
+    key: OverrideKey,
+    set: OverrideFields,
+}
+
+#[derive(Debug)]
+pub enum OverrideError {
+    Json(serde_json::Error),
+    /// A blank path or something that isn't an MBID, which would match the wrong tracks
+    Key(OverrideKey),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for OverrideError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "invalid override: {}", e),
+            Self::Key(key) => write!(f, "invalid override key: {:?}", key),
+            Self::Io(e) => write!(f, "couldn't save overrides: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OverrideError {}
+
+pub struct OverrideStore {
+    path: PathBuf,
+    overrides: Vec<Override>,
+}
+
+impl OverrideStore {
+    /// Loads the overrides saved at `path`, starting over if they're unreadable
+    pub fn load(path: PathBuf) -> Self {
+        let overrides = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|e| {
+                log::error!("Discarding unreadable overrides: {}", e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                log::error!("Couldn't read overrides: {}", e);
+                Vec::new()
+            }
+        };
+        Self { path, overrides }
+    }
+
+    /// Saves `overrides` and only then takes them on, so a failed save changes nothing
+    fn replace(&mut self, overrides: Vec<Override>) -> Result<(), OverrideError> {
+        // Written aside and renamed over, so a crash can't leave half a file
+        let temp_path = self.path.with_extension("json.tmp");
+        let json = serde_json::to_vec_pretty(&overrides).map_err(OverrideError::Json)?;
+        std::fs::write(&temp_path, json).map_err(OverrideError::Io)?;
+        std::fs::rename(&temp_path, &self.path).map_err(OverrideError::Io)?;
+        self.overrides = overrides;
+        Ok(())
+    }
+
+    /// Adds an override from its JSON, replacing any with the same key
+    pub fn add(&mut self, json: &str) -> Result<(), OverrideError> {
+        let mut added: Override = serde_json::from_str(json).map_err(OverrideError::Json)?;
+        added.key = added
+            .key
+            .clone()
+            .validate()
+            .ok_or(OverrideError::Key(added.key))?;
+        let mut overrides: Vec<Override> = self
+            .overrides
+            .iter()
+            .filter(|existing| existing.key != added.key)
+            .cloned()
+            .collect();
+        overrides.push(added);
+        self.replace(overrides)
+    }
+
+    /// Removes the override with the key given as JSON, returning whether there was one
+    pub fn remove(&mut self, key_json: &str) -> Result<bool, OverrideError> {
+        let key: OverrideKey = serde_json::from_str(key_json).map_err(OverrideError::Json)?;
+        // Stored keys are normalized, ones that never validated are still removed as they are
+        let key = key.clone().validate().unwrap_or(key);
+        let overrides: Vec<Override> = self
+            .overrides
+            .iter()
+            .filter(|existing| existing.key != key)
+            .cloned()
+            .collect();
+        if overrides.len() == self.overrides.len() {
+            return Ok(false);
+        }
+        self.replace(overrides).map(|_| true)
+    }
+
+    pub fn list(&self) -> String {
+        serde_json::to_string(&self.overrides).unwrap()
+    }
+
+    pub fn apply(&self, path: &Path, track_metadata: &mut TrackMetadata) {
+        // Keys are matched against the metadata as extracted, not as earlier overrides left it
+        let mut matching: Vec<&Override> = self
+            .overrides
+            .iter()
+            .filter(|o| o.key.matches(path, track_metadata))
+            .collect();
+        matching.sort_by_key(|o| o.key.specificity());
+        for o in matching {
+            log::info!("Applying override for {:?}", o.key);
+            o.set.apply(track_metadata);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+    use crate::{UUID_PATTERN, UUID_REGEX};
+
+    const RELEASE: &str = "0b1e1d2e-3f4a-4b5c-8d6e-7f8091a2b3c4";
+    const RECORDING: &str = "b10bbbfc-cf9e-42e0-be17-e2c3e1d2600d";
+    const PATH: &str = "/storage/emulated/0/Music/Album/01.flac";
+
+    fn store(name: &str) -> OverrideStore {
+        UUID_REGEX.get_or_init(|| Regex::new(UUID_PATTERN).unwrap());
+        let path = std::env::temp_dir().join(format!(
+            "lbp-overrides-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        OverrideStore::load(path)
+    }
+
+    fn track() -> TrackMetadata {
+        let mut track_metadata = TrackMetadata::default();
+        track_metadata.additional_info.release_mbid = RELEASE.to_string();
+        track_metadata.additional_info.recording_mbid = RECORDING.to_uppercase();
+        track_metadata
+    }
+
+    #[test]
+    fn specific_overrides_win() {
+        let mut store = store("specificity");
+        store
+            .add(&format!(
+                r#"{{"match": {{"path": "{}"}}, "set": {{"album": "Path"}}}}"#,
+                PATH
+            ))
+            .unwrap();
+        store
+            .add(&format!(
+                r#"{{"match": {{"recording_mbid": "{}"}}, "set": {{"album": "Recording", "title": "Title"}}}}"#,
+                RECORDING
+            ))
+            .unwrap();
+        store
+            .add(&format!(
+                r#"{{"match": {{"release_mbid": "{}"}}, "set": {{"album": "Release", "artist": "Artist"}}}}"#,
+                RELEASE
+            ))
+            .unwrap();
+
+        let mut track_metadata = track();
+        store.apply(Path::new(PATH), &mut track_metadata);
+        assert_eq!(track_metadata.release_name, "Path");
+        assert_eq!(track_metadata.track_name, "Title");
+        assert_eq!(track_metadata.artist_name, "Artist");
+
+        let mut track_metadata = track();
+        store.apply(Path::new("/elsewhere.flac"), &mut track_metadata);
+        assert_eq!(track_metadata.release_name, "Recording");
+        std::fs::remove_file(&store.path).unwrap();
+    }
+
+    #[test]
+    fn add_replaces_and_remove_removes() {
+        let mut store = store("replace");
+        let key = format!(r#"{{"release_mbid": "{}"}}"#, RELEASE.to_uppercase());
+        store
+            .add(&format!(
+                r#"{{"match": {}, "set": {{"album": "Old"}}}}"#,
+                key
+            ))
+            .unwrap();
+        store
+            .add(&format!(
+                r#"{{"match": {}, "set": {{"album": "New"}}}}"#,
+                key
+            ))
+            .unwrap();
+        assert_eq!(store.overrides.len(), 1);
+        let mut track_metadata = track();
+        store.apply(Path::new(PATH), &mut track_metadata);
+        assert_eq!(track_metadata.release_name, "New");
+
+        // Saved, so a reload sees the same
+        assert_eq!(OverrideStore::load(store.path.clone()).list(), store.list());
+
+        assert!(store.remove(&key).unwrap());
+        assert!(!store.remove(&key).unwrap());
+        assert!(store.overrides.is_empty());
+        std::fs::remove_file(&store.path).unwrap();
+    }
+
+    #[test]
+    fn blank_keys_match_nothing() {
+        let mut store = store("blank");
+        for key in [
+            r#"{"release_mbid": ""}"#,
+            r#"{"recording_mbid": "  "}"#,
+            r#"{"recording_mbid": "not an mbid"}"#,
+            r#"{"path": " "}"#,
+        ] {
+            let added = store.add(&format!(r#"{{"match": {}, "set": {{"album": "X"}}}}"#, key));
+            assert!(matches!(added, Err(OverrideError::Key(_))), "{}", key);
+        }
+        assert!(store.overrides.is_empty());
+
+        // Keys that got stored before they were checked
+        store.overrides.push(Override {
+            key: OverrideKey::ReleaseMbid(String::new()),
+            set: OverrideFields {
+                album: Some("X".to_string()),
+                ..Default::default()
+            },
+        });
+        let mut track_metadata = TrackMetadata::default();
+        store.apply(Path::new(""), &mut track_metadata);
+        assert_eq!(track_metadata.release_name, "");
+    }
+}
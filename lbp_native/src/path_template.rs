@@ -0,0 +1,157 @@
+//! Infers metadata for untagged files from where they sit in the library, with templates like
+//! `%artist%/%album%/%tracknumber% - %title%`
+
+use std::{path::Path, sync::OnceLock};
+
+use regex::Regex;
+
+use crate::tags::{Tag, TagKey};
+
+pub const DEFAULT_TEMPLATE: &str = "%artist%/%album%/%tracknumber% - %title%";
+
+static FIELD_REGEX: OnceLock<Regex> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct PathTemplate {
+    regex: Regex,
+    /// The field each capture group fills, `None` for fields we don't submit
+    fields: Vec<Option<TagKey>>,
+}
+
+impl PathTemplate {
+    /// Every `%field%` matches within a single path component, everything else has to match
+    /// exactly. Fields we don't submit, like `%year%`, still have to be there. The template is
+    /// matched against the end of the path, without the extension.
+    pub fn parse(template: &str) -> Result<Self, regex::Error> {
+        let template = template.trim_matches('/');
+        let mut pattern = String::from("(?:^|/)");
+        let mut fields = Vec::new();
+        let mut literal_start = 0;
+        let field_regex = FIELD_REGEX.get_or_init(|| Regex::new(r"%(\w+)%").unwrap());
+        for field in field_regex.captures_iter(template) {
+            let token = field.get(0).unwrap();
+            pattern.push_str(&regex::escape(&template[literal_start..token.start()]));
+            let key = field_key(&field[1]);
+            pattern.push_str(match key {
+                Some(TagKey::TrackNumber | TagKey::DiscNumber) => r"(\d+)",
+                _ => r"([^/]+?)",
+            });
+            fields.push(key);
+            literal_start = token.end();
+        }
+        pattern.push_str(&regex::escape(&template[literal_start..]));
+        pattern.push('$');
+        Ok(Self {
+            regex: Regex::new(&pattern)?,
+            fields,
+        })
+    }
+
+    pub fn infer(&self, path: &Path) -> Vec<Tag> {
+        let Some(path) = path.with_extension("").to_str().map(str::to_string) else {
+            return Vec::new();
+        };
+        let Some(captures) = self.regex.captures(&path) else {
+            log::debug!("Path doesn't match the template");
+            return Vec::new();
+        };
+        self.fields
+            .iter()
+            .zip(captures.iter().skip(1))
+            .filter_map(|(key, value)| {
+                Some(Tag {
+                    key: (*key)?,
+                    value: value?.as_str().trim().to_string(),
+                })
+            })
+            .filter(|tag| !tag.value.is_empty())
+            .collect()
+    }
+}
+
+fn field_key(field: &str) -> Option<TagKey> {
+    Some(match field.to_ascii_lowercase().as_str() {
+        "artist" => TagKey::Artist,
+        "albumartist" => TagKey::AlbumArtist,
+        "album" => TagKey::Album,
+        "title" => TagKey::Title,
+        "tracknumber" => TagKey::TrackNumber,
+        "discnumber" => TagKey::DiscNumber,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn infer(template: &str, path: &str) -> Vec<(TagKey, String)> {
+        PathTemplate::parse(template)
+            .unwrap()
+            .infer(Path::new(path))
+            .into_iter()
+            .map(|tag| (tag.key, tag.value))
+            .collect()
+    }
+
+    fn tags(tags: &[(TagKey, &str)]) -> Vec<(TagKey, String)> {
+        tags.iter()
+            .map(|(key, value)| (*key, value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn infers_default_template() {
+        assert_eq!(
+            infer(
+                DEFAULT_TEMPLATE,
+                "/sdcard/Music/Artist/Album/01 - Title.flac"
+            ),
+            tags(&[
+                (TagKey::Artist, "Artist"),
+                (TagKey::Album, "Album"),
+                (TagKey::TrackNumber, "01"),
+                (TagKey::Title, "Title"),
+            ])
+        );
+    }
+
+    #[test]
+    fn strips_extension() {
+        assert_eq!(
+            infer("%title%", "/sdcard/Music/Song.name.mp3"),
+            tags(&[(TagKey::Title, "Song.name")])
+        );
+    }
+
+    #[test]
+    fn fields_stay_in_their_component() {
+        assert_eq!(
+            infer(
+                "%artist% - %title%",
+                "/sdcard/Music/Folder/Artist - Title.ogg"
+            ),
+            tags(&[(TagKey::Artist, "Artist"), (TagKey::Title, "Title")])
+        );
+    }
+
+    #[test]
+    fn escapes_literal_text() {
+        let template = "%artist%/(%year%) [%album%]/%title%";
+        assert_eq!(
+            infer(template, "/Music/Artist/(1999) [Album]/Title.opus"),
+            tags(&[
+                (TagKey::Artist, "Artist"),
+                (TagKey::Album, "Album"),
+                (TagKey::Title, "Title"),
+            ])
+        );
+        assert!(infer(template, "/Music/Artist/1999 Album/Title.opus").is_empty());
+    }
+
+    #[test]
+    fn short_paths_dont_match() {
+        assert!(infer(DEFAULT_TEMPLATE, "Album/01 - Title.flac").is_empty());
+        assert!(infer(DEFAULT_TEMPLATE, "/01 - Title.flac").is_empty());
+    }
+}
@@ -0,0 +1,82 @@
+//! Listens the server permanently rejects, set aside with its reason so they stop blocking the
+//! cached backlog. They're kept in the app's files rather than its cache, since they're the
+//! only copy and the user decides when they go.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct QuarantinedListen {
+    /// The file name, which identifies it to the app
+    #[serde(default, skip_deserializing)]
+    id: String,
+    error: String,
+    quarantined_at: u64,
+    /// As it was submitted, or as a string if that wasn't even JSON
+    listen: serde_json::Value,
+}
+
+/// Files a rejected listen away under `name` with the server's reason, numbering the name when
+/// it's taken so another listen isn't replaced
+pub fn add(dir: &Path, name: &str, listen: &[u8], error: &str) -> io::Result<()> {
+    let entry = QuarantinedListen {
+        id: String::new(),
+        error: error.to_string(),
+        quarantined_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        listen: serde_json::from_slice(listen).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(listen).into_owned())
+        }),
+    };
+    std::fs::create_dir_all(dir)?;
+    let (stem, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    for n in 0.. {
+        let path = match n {
+            0 => dir.join(name),
+            n => dir.join(format!("{}-{}.{}", stem, n, ext)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(mut file) => return file.write_all(&serde_json::to_vec(&entry).unwrap()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Every quarantined listen as a JSON array, oldest listen first
+pub fn list(dir: &Path) -> String {
+    let mut entries: Vec<QuarantinedListen> = dir
+        .read_dir()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let json = std::fs::read(entry.path()).ok()?;
+            let mut listen: QuarantinedListen = serde_json::from_slice(&json)
+                .inspect_err(|e| log::warn!("Unreadable quarantined listen: {}", e))
+                .ok()?;
+            listen.id = entry.file_name().into_string().ok()?;
+            Some(listen)
+        })
+        .collect();
+    entries.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    serde_json::to_string(&entries).unwrap()
+}
+
+/// Discards a quarantined listen, returning whether there was one
+pub fn remove(dir: &Path, id: &str) -> bool {
+    // Only ever a name inside the quarantine
+    if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+        return false;
+    }
+    std::fs::remove_file(dir.join(id)).is_ok()
+}
@@ -0,0 +1,287 @@
+//! User-editable rewrite rules applied to the metadata before it's submitted, for cleaning up
+//! what taggers and labels leave in titles and fixing artists that are known to be mistagged.
+//!
+//! Rules are a JSON array, applied in order:
+//!
+//! ```json
+//! [
+//!     { "field": "title", "match": "\\s*\\((\\d{4} )?Remaster(ed)?( \\d{4})?\\)$", "replace": "" },
+//!     { "field": "artist", "match": "(?i)\\s+(ft\\.?|featuring)\\s+", "replace": " feat. " },
+//!     { "field": "artist", "match": "^Beatles$", "replace": "The Beatles", "when": { "album": "^Abbey Road" } }
+//! ]
+//! ```
+//!
+//! `replace` may refer to capture groups as `$1` or `${name}` and defaults to removing the match.
+//! A rule only applies when every regex in `when` matches its field.
+
+use std::{collections::BTreeMap, fmt};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::TrackMetadata;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+enum Field {
+    Artist,
+    Title,
+    Album,
+}
+
+impl Field {
+    fn get(self, track_metadata: &TrackMetadata) -> &str {
+        match self {
+            Self::Artist => &track_metadata.artist_name,
+            Self::Title => &track_metadata.track_name,
+            Self::Album => &track_metadata.release_name,
+        }
+    }
+
+    fn get_mut(self, track_metadata: &mut TrackMetadata) -> &mut String {
+        match self {
+            Self::Artist => &mut track_metadata.artist_name,
+            Self::Title => &mut track_metadata.track_name,
+            Self::Album => &mut track_metadata.release_name,
+        }
+    }
+}
+
+/// A rule as written, before its regexes are compiled
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    field: Field,
+    #[serde(rename = "match")]
+    pattern: String,
+    #[serde(default)]
+    replace: String,
+    #[serde(default)]
+    when: BTreeMap<Field, String>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    field: Field,
+    regex: Regex,
+    replace: String,
+    conditions: Vec<(Field, Regex)>,
+}
+
+#[derive(Debug)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+pub enum RuleError {
+    Json(serde_json::Error),
+    /// A rule's regex doesn't compile, rules are numbered from 1
+    Regex {
+        rule: usize,
+        error: regex::Error,
+    },
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "invalid rules: {}", e),
+            Self::Regex { rule, error } => write!(f, "invalid regex in rule {}: {}", rule, error),
+        }
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+impl RuleSet {
+    pub const fn new() -> Self {
+        Self { rules: Vec::new() }
+    }
+
+    /// Parses and compiles a rule set, an empty string being no rules at all
+    pub fn parse(json: &str) -> Result<Self, RuleError> {
+        if json.trim().is_empty() {
+            return Ok(Self::new());
+        }
+        let specs: Vec<RuleSpec> = serde_json::from_str(json).map_err(RuleError::Json)?;
+        let rules = specs
+            .into_iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                let compile = |pattern: &str| {
+                    Regex::new(pattern).map_err(|error| RuleError::Regex { rule: i + 1, error })
+                };
+                Ok(Rule {
+                    field: spec.field,
+                    regex: compile(&spec.pattern)?,
+                    replace: spec.replace,
+                    conditions: spec
+                        .when
+                        .iter()
+                        .map(|(&field, pattern)| Ok((field, compile(pattern)?)))
+                        .collect::<Result<_, RuleError>>()?,
+                })
+            })
+            .collect::<Result<_, RuleError>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn apply(&self, track_metadata: &mut TrackMetadata) {
+        for rule in &self.rules {
+            let applies = rule
+                .conditions
+                .iter()
+                .all(|(field, regex)| regex.is_match(field.get(track_metadata)));
+            if !applies {
+                continue;
+            }
+            let value = rule.field.get_mut(track_metadata);
+            let rewritten = rule.regex.replace_all(value, rule.replace.as_str());
+            let rewritten = rewritten.trim();
+            if rewritten != value {
+                log::info!(
+                    "Rewrote {:?} \"{}\" to \"{}\"",
+                    rule.field,
+                    value,
+                    rewritten
+                );
+                *value = rewritten.to_string();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(artist: &str, title: &str, album: &str) -> TrackMetadata {
+        TrackMetadata {
+            artist_name: artist.to_string(),
+            track_name: title.to_string(),
+            release_name: album.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn strips_remaster_suffix() {
+        let rules = RuleSet::parse(
+            r#"[{ "field": "title", "match": "\\s*\\((\\d{4} )?Remaster(ed)?( \\d{4})?\\)$" }]"#,
+        )
+        .unwrap();
+        let mut metadata = track(
+            "The Beatles",
+            "Come Together (Remastered 2009)",
+            "Abbey Road",
+        );
+        rules.apply(&mut metadata);
+        assert_eq!(metadata.track_name, "Come Together");
+
+        let mut metadata = track(
+            "Queen",
+            "Bohemian Rhapsody (2011 Remaster)",
+            "A Night at the Opera",
+        );
+        rules.apply(&mut metadata);
+        assert_eq!(metadata.track_name, "Bohemian Rhapsody");
+    }
+
+    #[test]
+    fn normalizes_featuring() {
+        let rules = RuleSet::parse(
+            r#"[{ "field": "artist", "match": "(?i)\\s+(ft\\.?|featuring)\\s+", "replace": " feat. " }]"#,
+        )
+        .unwrap();
+        let mut metadata = track("Daft Punk Featuring Pharrell Williams", "Get Lucky", "");
+        rules.apply(&mut metadata);
+        assert_eq!(metadata.artist_name, "Daft Punk feat. Pharrell Williams");
+    }
+
+    #[test]
+    fn conditions_gate_rules() {
+        let rules = RuleSet::parse(
+            r#"[{
+                "field": "artist",
+                "match": "^Beatles$",
+                "replace": "The Beatles",
+                "when": { "album": "^Abbey Road" }
+            }]"#,
+        )
+        .unwrap();
+        let mut metadata = track("Beatles", "Something", "Abbey Road");
+        rules.apply(&mut metadata);
+        assert_eq!(metadata.artist_name, "The Beatles");
+
+        let mut metadata = track("Beatles", "Something", "Cover Album");
+        rules.apply(&mut metadata);
+        assert_eq!(metadata.artist_name, "Beatles");
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let rules = RuleSet::parse(
+            r#"[
+                { "field": "title", "match": "Intro", "replace": "Overture" },
+                { "field": "album", "match": ".*", "replace": "Best Of", "when": { "title": "^Overture$" } }
+            ]"#,
+        )
+        .unwrap();
+        let mut metadata = track("", "Intro", "Live");
+        rules.apply(&mut metadata);
+        assert_eq!(metadata.release_name, "Best Of");
+    }
+
+    #[test]
+    fn capture_groups() {
+        let rules = RuleSet::parse(
+            r#"[{ "field": "title", "match": "^(?<title>.+) - (?<version>.+ Mix)$", "replace": "${title} (${version})" }]"#,
+        )
+        .unwrap();
+        let mut metadata = track("", "Blue Monday - Extended Mix", "");
+        rules.apply(&mut metadata);
+        assert_eq!(metadata.track_name, "Blue Monday (Extended Mix)");
+    }
+
+    #[test]
+    fn empty_is_no_rules() {
+        assert_eq!(RuleSet::parse("").unwrap().len(), 0);
+        assert_eq!(RuleSet::parse("  \n").unwrap().len(), 0);
+        assert_eq!(RuleSet::parse("[]").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn reports_invalid_regex_with_rule_number() {
+        let error = RuleSet::parse(
+            r#"[
+                { "field": "title", "match": "ok" },
+                { "field": "title", "match": "ok", "when": { "artist": "(unclosed" } }
+            ]"#,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, RuleError::Regex { rule: 2, .. }),
+            "{}",
+            error
+        );
+        assert!(error.to_string().starts_with("invalid regex in rule 2"));
+    }
+
+    #[test]
+    fn reports_invalid_json() {
+        for json in [
+            "{",
+            r#"[{ "field": "genre", "match": "x" }]"#,
+            r#"[{ "field": "title" }]"#,
+            r#"[{ "field": "title", "match": "x", "replcae": "y" }]"#,
+        ] {
+            let error = RuleSet::parse(json).unwrap_err();
+            assert!(matches!(error, RuleError::Json(_)), "{}: {}", json, error);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use flume::{Receiver, Sender};
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::{quarantine, validation, Payload};
+
+/// Upper bound on a whole request, so a dead network can't hold the queue forever
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shared by every session so connections, HTTP/2 streams and TLS sessions get reused
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+pub fn init_client() {
+    HTTP_CLIENT
+        .set(
+            reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+}
+
+pub fn http_client() -> &'static reqwest::Client {
+    HTTP_CLIENT.get().unwrap()
+}
+
+#[derive(Serialize, Debug)]
+struct ListenbrainzSingleListen<'a> {
+    listen_type: &'static str,
+    payload: [&'a Payload; 1],
+}
+
+#[derive(Debug)]
+pub enum Submission {
+    Listen {
+        listen_type: &'static str,
+        payload: Box<Payload>,
+        token: String,
+    },
+    ImportCache {
+        token: String,
+    },
+}
+
+/// Queues network requests for the submission worker. Never blocks.
+#[derive(Debug, Clone)]
+pub struct Submitter(Sender<Submission>);
+
+impl Submitter {
+    pub fn new() -> (Self, Receiver<Submission>) {
+        let (tx, rx) = flume::unbounded();
+        (Self(tx), rx)
+    }
+
+    pub fn listen(&self, listen_type: &'static str, payload: Payload, token: String) {
+        self.send(Submission::Listen {
+            listen_type,
+            payload: Box::new(payload),
+            token,
+        });
+    }
+
+    pub fn import_cache(&self, token: String) {
+        self.send(Submission::ImportCache { token });
+    }
+
+    fn send(&self, submission: Submission) {
+        if let Err(e) = self.0.send(submission) {
+            log::error!("Submission worker is gone, dropping {:?}", e.into_inner());
+        }
+    }
+}
+
+/// Where cached listens live, and where the ones the server rejects are moved
+pub struct CachePaths {
+    pub cache: PathBuf,
+    pub quarantine: PathBuf,
+}
+
+/// Works through queued submissions one at a time until every [`Submitter`] is dropped
+pub async fn submission_worker(rx: Receiver<Submission>, paths: CachePaths) {
+    let client = http_client();
+    while let Ok(submission) = rx.recv_async().await {
+        match submission {
+            Submission::Listen {
+                listen_type,
+                mut payload,
+                token,
+            } => scrobble(client, listen_type, &mut payload, &token, &paths).await,
+            Submission::ImportCache { token } => import_cache(client, &token, &paths).await,
+        }
+    }
+    log::info!("Submission worker finished");
+}
+
+/// How the server took a submission
+enum Outcome {
+    Accepted,
+    /// The server refuses the listens as they are, and says why
+    Rejected(String),
+    /// Worth trying again later, the network, the server or the token were at fault
+    Failed,
+}
+
+impl Outcome {
+    async fn of(response: reqwest::Result<reqwest::Response>) -> Self {
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Error submitting: {}", e);
+                return Self::Failed;
+            }
+        };
+        let status = response.status();
+        if status.is_success() {
+            return Self::Accepted;
+        }
+        log::error!("Error submitting: {:?}", status);
+        let permanent = status.is_client_error()
+            && !matches!(
+                status,
+                StatusCode::UNAUTHORIZED
+                    | StatusCode::FORBIDDEN
+                    | StatusCode::REQUEST_TIMEOUT
+                    | StatusCode::TOO_MANY_REQUESTS
+            );
+        if !permanent {
+            return Self::Failed;
+        }
+        let body = response.text().await.unwrap_or_default();
+        // Errors are `{"code": 400, "error": "…"}`
+        let error = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|json| json["error"].as_str().map(str::to_string))
+            .unwrap_or(body);
+        Self::Rejected(format!("{}: {}", status, error))
+    }
+}
+
+async fn scrobble(
+    client: &reqwest::Client,
+    listen_type: &'static str,
+    payload: &mut Payload,
+    token: &str,
+    paths: &CachePaths,
+) {
+    // The server would reject it, and go on rejecting it from the cache
+    match validation::validate(listen_type, payload) {
+        Ok(repairs) => {
+            for repair in repairs {
+                log::warn!(
+                    "Submitting {:?}: {}",
+                    payload.track_metadata.track_name,
+                    repair
+                );
+            }
+        }
+        Err(e) => {
+            log::error!("Not submitting {}: {}", listen_type, e);
+            return;
+        }
+    }
+    let send = ListenbrainzSingleListen {
+        listen_type,
+        payload: [payload],
+    };
+    #[cfg(debug_assertions)]
+    log::debug!("{}", serde_json::to_string_pretty(&send).unwrap());
+    let response = client
+        .post("https://api.listenbrainz.org/1/submit-listens")
+        .header("Authorization", token)
+        .json(&send)
+        .send()
+        .await;
+    let outcome = Outcome::of(response).await;
+    if let Outcome::Accepted = outcome {
+        import_cache(client, token, paths).await;
+        return;
+    }
+    // Playing now notifications are only worth anything at the time
+    let Some(listened_at) = payload.listened_at else {
+        return;
+    };
+    let name = format!("{}.json", listened_at);
+    if let Outcome::Rejected(error) = outcome {
+        log::warn!("Quarantining {}: {}", name, error);
+        let listen = serde_json::to_vec(&payload).unwrap();
+        if let Err(e) = quarantine::add(&paths.quarantine, &name, &listen, &error) {
+            log::error!("Couldn't quarantine {}: {}", name, e);
+        }
+        return;
+    }
+    let path = paths.cache.join(name);
+    let written = std::fs::File::create(&path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &payload)?;
+        writer.flush()
+    });
+    if let Err(e) = written {
+        log::error!("Couldn't cache {}: {}", path.display(), e);
+    }
+}
+
+/// A listen waiting in the cache
+struct CachedListen {
+    path: PathBuf,
+    json: Vec<u8>,
+}
+
+async fn import_cache(client: &reqwest::Client, token: &str, paths: &CachePaths) {
+    let Ok(read_dir) = paths.cache.read_dir() else {
+        return;
+    };
+    let mut listens: Vec<CachedListen> = read_dir
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let json = std::fs::read(&path)
+                .inspect_err(|e| log::error!("Couldn't read {}: {}", path.display(), e))
+                .ok()?;
+            Some(CachedListen { path, json })
+        })
+        .collect();
+    if listens.is_empty() {
+        return;
+    }
+    listens.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    import_batch(client, token, &listens, &paths.quarantine).await;
+}
+
+/// Submits cached listens, narrowing a rejected batch down by halves to the listens the server
+/// won't take so the rest still go through. Returns `false` when a failure worth retrying
+/// means the rest should stay cached.
+async fn import_batch(
+    client: &reqwest::Client,
+    token: &str,
+    listens: &[CachedListen],
+    quarantine_path: &Path,
+) -> bool {
+    let mut request = if listens.len() == 1 {
+        br#"{"listen_type":"single","payload":["#.to_vec()
+    } else {
+        br#"{"listen_type":"import","payload":["#.to_vec()
+    };
+    for listen in listens {
+        request.extend_from_slice(&listen.json);
+        request.push(b',');
+    }
+    request.pop();
+    request.extend_from_slice(b"]}");
+    #[cfg(debug_assertions)]
+    log::debug!("{}", String::from_utf8_lossy(&request));
+    let response = client
+        .post("https://api.listenbrainz.org/1/submit-listens")
+        .header("Authorization", token)
+        .header("Content-Type", "json")
+        .body(request)
+        .send()
+        .await;
+
+    match Outcome::of(response).await {
+        Outcome::Accepted => {
+            for listen in listens {
+                if let Err(e) = std::fs::remove_file(&listen.path) {
+                    log::error!("Couldn't remove {}: {}", listen.path.display(), e);
+                }
+            }
+            true
+        }
+        Outcome::Failed => false,
+        Outcome::Rejected(error) if listens.len() == 1 => {
+            let listen = &listens[0];
+            let name = listen.path.file_name().unwrap().to_string_lossy();
+            log::warn!("Quarantining {}: {}", name, error);
+            match quarantine::add(quarantine_path, &name, &listen.json, &error) {
+                Ok(()) => {
+                    let _ = std::fs::remove_file(&listen.path);
+                    true
+                }
+                Err(e) => {
+                    log::error!("Couldn't quarantine {}: {}", name, e);
+                    false
+                }
+            }
+        }
+        Outcome::Rejected(_) => {
+            let (first, second) = listens.split_at(listens.len() / 2);
+            Box::pin(import_batch(client, token, first, quarantine_path)).await
+                && Box::pin(import_batch(client, token, second, quarantine_path)).await
+        }
+    }
+}
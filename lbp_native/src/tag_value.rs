@@ -0,0 +1,64 @@
+#[cfg(feature = "symphonia")]
+use symphonia::core::meta::Value;
+use unicode_normalization::UnicodeNormalization;
+
+/// Turns a tag value into text, whatever the container stored it as.
+///
+/// Returns `None` for values that carry no usable text, like flags or binary blobs that don't
+/// decode to anything printable.
+#[cfg(feature = "symphonia")]
+pub fn decode_value(value: Value) -> Option<String> {
+    match value {
+        Value::String(text) => clean_text(&text),
+        Value::Binary(bytes) => decode_bytes(&bytes),
+        Value::UnsignedInt(n) => Some(n.to_string()),
+        Value::SignedInt(n) => Some(n.to_string()),
+        Value::Float(n) => Some(n.to_string()),
+        Value::Boolean(_) | Value::Flag => None,
+    }
+}
+
+/// Decodes raw tag bytes, trying UTF-16 (when there's a byte order mark), then UTF-8, then
+/// Latin-1, which is what legacy taggers wrote when they didn't say.
+#[cfg(any(feature = "symphonia", feature = "lofty"))]
+pub fn decode_bytes(bytes: &[u8]) -> Option<String> {
+    let text = match bytes {
+        [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, u16::from_le_bytes)?,
+        [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, u16::from_be_bytes)?,
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8(rest.to_vec()).ok()?,
+        _ => match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => bytes.iter().map(|&b| b as char).collect(),
+        },
+    };
+    clean_text(&text)
+}
+
+#[cfg(any(feature = "symphonia", feature = "lofty"))]
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Option<String> {
+    let units = bytes
+        .chunks_exact(2)
+        .map(|unit| from_bytes([unit[0], unit[1]]));
+    char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()
+}
+
+/// Strips null padding and surrounding whitespace and normalizes to NFC, so the same name
+/// always submits the same way. Text with control characters left in it is garbage.
+pub fn clean_text(text: &str) -> Option<String> {
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if text.is_empty() || text.chars().any(|c| c.is_control() && c != '\0') {
+        return None;
+    }
+    Some(text.nfc().collect())
+}
+
+/// What's left of a name to compare once case and punctuation, which vary between sources, are
+/// gone
+pub fn comparable(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
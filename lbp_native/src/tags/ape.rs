@@ -0,0 +1,61 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{
+    invalid, is_cue_sheet_key, picard, push_text, read_array, read_block, take, take_u32_le, Tag,
+    TagKey,
+};
+
+const FOOTER_LEN: u64 = 32;
+
+/// Reads the APEv2 tag whose footer ends at `end`, returning whether there was one
+pub fn read<R: Read + Seek>(reader: &mut R, end: u64, tags: &mut Vec<Tag>) -> io::Result<bool> {
+    if end < FOOTER_LEN {
+        return Ok(false);
+    }
+    reader.seek(SeekFrom::Start(end - FOOTER_LEN))?;
+    let footer: [u8; FOOTER_LEN as usize] = read_array(reader)?;
+    if &footer[..8] != b"APETAGEX" {
+        return Ok(false);
+    }
+    // The size covers the items and the footer, but not the optional header
+    let size = u32::from_le_bytes(footer[12..16].try_into().unwrap()) as u64;
+    let count = u32::from_le_bytes(footer[16..20].try_into().unwrap());
+    if size < FOOTER_LEN || size > end {
+        return Err(invalid("APEv2 tag size out of range"));
+    }
+    reader.seek(SeekFrom::Start(end - size))?;
+    let items = read_block(reader, size - FOOTER_LEN)?;
+
+    let mut pos = 0;
+    for _ in 0..count {
+        let len = take_u32_le(&items, &mut pos)? as usize;
+        let flags = take_u32_le(&items, &mut pos)?;
+        let key_len = items[pos..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| invalid("unterminated APEv2 item key"))?;
+        let key = String::from_utf8_lossy(take(&items, &mut pos, key_len)?);
+        pos += 1;
+        let value = take(&items, &mut pos, len)?;
+        // Bits 1-2 give the item type, 0 is UTF-8 text and the rest are binary or links
+        if flags & 0x6 != 0 {
+            continue;
+        }
+        if is_cue_sheet_key(&key) {
+            tags.push(Tag {
+                key: TagKey::CueSheet,
+                value: String::from_utf8_lossy(value).into_owned(),
+            });
+            continue;
+        }
+        let Some(key) = picard::ape(&key) else {
+            continue;
+        };
+        for value in value.split(|&b| b == 0) {
+            if let Ok(value) = std::str::from_utf8(value) {
+                push_text(tags, key, value);
+            }
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,41 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{id3v2, invalid, read_array, Tag};
+
+/// DSF files point to an ID3v2 tag at the end of the file from their header
+pub fn read_dsf<R: Read + Seek>(reader: &mut R, tags: &mut Vec<Tag>) -> io::Result<()> {
+    let header: [u8; 28] = read_array(reader)?;
+    if &header[..4] != b"DSD " {
+        return Err(invalid("missing DSF header"));
+    }
+    let metadata = u64::from_le_bytes(header[20..28].try_into().unwrap());
+    if metadata != 0 {
+        reader.seek(SeekFrom::Start(metadata))?;
+        id3v2::read(reader, tags)?;
+    }
+    Ok(())
+}
+
+/// DSDIFF has no tag format of its own, but taggers put an ID3v2 tag in an `ID3 ` chunk
+pub fn read_dff<R: Read + Seek>(reader: &mut R, len: u64, tags: &mut Vec<Tag>) -> io::Result<()> {
+    let header: [u8; 16] = read_array(reader)?;
+    if &header[..4] != b"FRM8" || &header[12..16] != b"DSD " {
+        return Err(invalid("missing DSDIFF header"));
+    }
+    let mut pos = 16;
+    while pos + 12 <= len {
+        reader.seek(SeekFrom::Start(pos))?;
+        let chunk: [u8; 12] = read_array(reader)?;
+        let size = u64::from_be_bytes(chunk[4..12].try_into().unwrap());
+        if &chunk[..4] == b"ID3 " {
+            id3v2::read(reader, tags)?;
+            return Ok(());
+        }
+        // Chunks are padded to an even length
+        pos = size
+            .checked_add(12 + size % 2)
+            .and_then(|chunk_len| pos.checked_add(chunk_len))
+            .ok_or_else(|| invalid("DSDIFF chunk size out of range"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,262 @@
+//! Synthetic files for every container the tag block readers handle, shared by the tests and
+//! the `tag_readers` bench. Every one carries the same Picard style tags and large cover art.
+
+/// Cover art large enough that reading it instead of skipping it shows
+pub const COVER_LEN: usize = 512 * 1024;
+
+/// Every fixture as a file name and its contents
+pub fn all() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("id3v24.mp3", mp3(4)),
+        ("id3v23.mp3", mp3(3)),
+        ("picard.flac", flac()),
+        ("picard.ogg", ogg()),
+        ("picard.m4a", m4a()),
+        ("picard.wv", wavpack()),
+        ("picard.dsf", dsf()),
+    ]
+}
+
+pub const TEXT_TAGS: [(&str, &str); 8] = [
+    ("TITLE", "Fixture Title"),
+    ("ARTIST", "First Artist & Second Artist"),
+    ("ARTISTS", "First Artist"),
+    ("ARTISTS", "Second Artist"),
+    ("ALBUM", "Fixture Album"),
+    ("TRACKNUMBER", "3"),
+    (
+        "MUSICBRAINZ_TRACKID",
+        "5d5b6e4e-59f5-4d5b-8ce1-2d1a8a30d5f4",
+    ),
+    (
+        "MUSICBRAINZ_ALBUMID",
+        "0b3c9e6e-8d6f-4e8b-9a37-3e4f8d1c2b7a",
+    ),
+];
+
+pub fn mp3(major: u8) -> Vec<u8> {
+    let mut data = id3v2(major);
+    // MPEG-1 layer III, 128 kbps, 44.1 kHz frames of silence
+    for _ in 0..400 {
+        data.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x64]);
+        data.resize(data.len() + 413, 0);
+    }
+    data
+}
+
+pub fn id3v2(major: u8) -> Vec<u8> {
+    let frame_id = |key: &str| match key {
+        "TITLE" => "TIT2",
+        "ARTIST" => "TPE1",
+        "ALBUM" => "TALB",
+        "TRACKNUMBER" => "TRCK",
+        "MUSICBRAINZ_TRACKID" => "UFID",
+        _ => "TXXX",
+    };
+    let txxx_desc = |key: &str| match key {
+        "ARTISTS" => "ARTISTS",
+        _ => "MusicBrainz Album Id",
+    };
+
+    let mut frames = Vec::new();
+    for (key, value) in TEXT_TAGS {
+        let id = frame_id(key);
+        if id == "UFID" {
+            let body = [b"http://musicbrainz.org\0".as_slice(), value.as_bytes()].concat();
+            id3_frame(&mut frames, major, id, &body);
+            continue;
+        }
+        let mut body = vec![3];
+        if id == "TXXX" {
+            body.extend_from_slice(txxx_desc(key).as_bytes());
+            body.push(0);
+        }
+        body.extend_from_slice(value.as_bytes());
+        id3_frame(&mut frames, major, id, &body);
+    }
+    let mut apic = b"\0image/jpeg\0\x03\0".to_vec();
+    apic.resize(apic.len() + COVER_LEN, 0xAB);
+    id3_frame(&mut frames, major, "APIC", &apic);
+
+    let mut data = b"ID3".to_vec();
+    data.extend_from_slice(&[major, 0, 0]);
+    data.extend_from_slice(&syncsafe(frames.len() as u32));
+    data.extend_from_slice(&frames);
+    data
+}
+
+pub fn id3_frame(frames: &mut Vec<u8>, major: u8, id: &str, body: &[u8]) {
+    frames.extend_from_slice(id.as_bytes());
+    if major == 4 {
+        frames.extend_from_slice(&syncsafe(body.len() as u32));
+    } else {
+        frames.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    }
+    frames.extend_from_slice(&[0, 0]);
+    frames.extend_from_slice(body);
+}
+
+pub fn syncsafe(n: u32) -> [u8; 4] {
+    [
+        (n >> 21) as u8 & 0x7F,
+        (n >> 14) as u8 & 0x7F,
+        (n >> 7) as u8 & 0x7F,
+        n as u8 & 0x7F,
+    ]
+}
+
+pub fn vorbis_comments() -> Vec<u8> {
+    let vendor = b"lbp_native bench";
+    let mut data = (vendor.len() as u32).to_le_bytes().to_vec();
+    data.extend_from_slice(vendor);
+    data.extend_from_slice(&(TEXT_TAGS.len() as u32).to_le_bytes());
+    for (key, value) in TEXT_TAGS {
+        let comment = format!("{}={}", key, value);
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+    data
+}
+
+pub fn flac() -> Vec<u8> {
+    let mut data = b"fLaC".to_vec();
+    // STREAMINFO: 4096 sample blocks, 44.1 kHz stereo 16 bit, 10 seconds
+    let mut streaminfo = vec![0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0];
+    let info: u64 = (44100 << 44) | (1 << 41) | (15 << 36) | 441000;
+    streaminfo.extend_from_slice(&info.to_be_bytes());
+    streaminfo.extend_from_slice(&[0; 16]);
+    flac_block(&mut data, 0, false, &streaminfo);
+    flac_block(&mut data, 4, false, &vorbis_comments());
+    flac_block(&mut data, 6, true, &vec![0xAB; COVER_LEN]);
+    data
+}
+
+pub fn flac_block(data: &mut Vec<u8>, block_type: u8, last: bool, body: &[u8]) {
+    data.push(block_type | if last { 0x80 } else { 0 });
+    data.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    data.extend_from_slice(body);
+}
+
+pub fn ogg() -> Vec<u8> {
+    let mut ident = b"\x01vorbis".to_vec();
+    ident.extend_from_slice(&[0; 4]);
+    ident.push(2);
+    ident.extend_from_slice(&44100u32.to_le_bytes());
+    ident.extend_from_slice(&[0; 12]);
+    ident.extend_from_slice(&[0xB8, 1]);
+
+    let mut comment = b"\x03vorbis".to_vec();
+    comment.extend_from_slice(&vorbis_comments());
+    comment.push(1);
+
+    let mut data = Vec::new();
+    ogg_page(&mut data, 0, &ident);
+    ogg_page(&mut data, 1, &comment);
+    data
+}
+
+pub fn ogg_page(data: &mut Vec<u8>, sequence: u32, packet: &[u8]) {
+    data.extend_from_slice(b"OggS\0");
+    data.push(if sequence == 0 { 2 } else { 0 });
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&sequence.to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    let mut lacing = vec![255; packet.len() / 255];
+    lacing.push((packet.len() % 255) as u8);
+    data.push(lacing.len() as u8);
+    data.extend_from_slice(&lacing);
+    data.extend_from_slice(packet);
+}
+
+pub fn m4a() -> Vec<u8> {
+    let text_item = |kind: &[u8], value: &str| {
+        let mut body = 1u32.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0; 4]);
+        body.extend_from_slice(value.as_bytes());
+        atom(kind, &atom(b"data", &body))
+    };
+    let freeform_item = |name: &str, value: &str| {
+        let mut body = atom(
+            b"mean",
+            &[b"\0\0\0\0".as_slice(), b"com.apple.iTunes"].concat(),
+        );
+        body.extend(atom(b"name", &[b"\0\0\0\0", name.as_bytes()].concat()));
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(value.as_bytes());
+        body.extend(atom(b"data", &data));
+        atom(b"----", &body)
+    };
+
+    let mut ilst = Vec::new();
+    ilst.extend(text_item(b"\xA9nam", "Fixture Title"));
+    ilst.extend(text_item(b"\xA9ART", "First Artist & Second Artist"));
+    ilst.extend(text_item(b"\xA9alb", "Fixture Album"));
+    ilst.extend(freeform_item("ARTISTS", "First Artist"));
+    ilst.extend(freeform_item("ARTISTS", "Second Artist"));
+    ilst.extend(freeform_item(
+        "MusicBrainz Album Id",
+        "0b3c9e6e-8d6f-4e8b-9a37-3e4f8d1c2b7a",
+    ));
+    let mut covr = 13u32.to_be_bytes().to_vec();
+    covr.extend_from_slice(&[0; 4]);
+    covr.resize(covr.len() + COVER_LEN, 0xAB);
+    ilst.extend(atom(b"covr", &atom(b"data", &covr)));
+
+    let mut hdlr = vec![0; 8];
+    hdlr.extend_from_slice(b"mdirappl");
+    hdlr.extend_from_slice(&[0; 10]);
+    let mut meta = vec![0; 4];
+    meta.extend(atom(b"hdlr", &hdlr));
+    meta.extend(atom(b"ilst", &ilst));
+
+    let mut data = atom(b"ftyp", b"M4A \0\0\0\0M4A mp42isom");
+    data.extend(atom(b"mdat", &vec![0; 64 * 1024]));
+    data.extend(atom(b"moov", &atom(b"udta", &atom(b"meta", &meta))));
+    data
+}
+
+pub fn wavpack() -> Vec<u8> {
+    // A WavPack block header and some audio, which the tag reader never looks at
+    let mut data = b"wvpk".to_vec();
+    data.resize(256 * 1024, 0);
+
+    let mut items = Vec::new();
+    for (key, value) in TEXT_TAGS {
+        let key = if key == "TRACKNUMBER" { "Track" } else { key };
+        items.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        items.extend_from_slice(&[0; 4]);
+        items.extend_from_slice(key.as_bytes());
+        items.push(0);
+        items.extend_from_slice(value.as_bytes());
+    }
+    data.extend_from_slice(&items);
+    data.extend_from_slice(b"APETAGEX");
+    data.extend_from_slice(&2000u32.to_le_bytes());
+    data.extend_from_slice(&(items.len() as u32 + 32).to_le_bytes());
+    data.extend_from_slice(&(TEXT_TAGS.len() as u32).to_le_bytes());
+    data.extend_from_slice(&[0; 12]);
+    data
+}
+
+pub fn dsf() -> Vec<u8> {
+    let audio_len = 256 * 1024;
+    let tag = id3v2(3);
+    let metadata = 28 + audio_len;
+
+    let mut data = b"DSD ".to_vec();
+    data.extend_from_slice(&28u64.to_le_bytes());
+    data.extend_from_slice(&((metadata + tag.len()) as u64).to_le_bytes());
+    data.extend_from_slice(&(metadata as u64).to_le_bytes());
+    data.resize(metadata, 0);
+    data.extend_from_slice(&tag);
+    data
+}
+
+pub fn atom(kind: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(body);
+    data
+}
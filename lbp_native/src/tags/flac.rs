@@ -0,0 +1,28 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{invalid, read_array, read_block, vorbis, Tag};
+
+const VORBIS_COMMENT: u8 = 4;
+
+/// Walks the metadata blocks at the start of a FLAC stream, skipping everything but the
+/// Vorbis comment (pictures especially)
+pub fn read<R: Read + Seek>(reader: &mut R, tags: &mut Vec<Tag>) -> io::Result<()> {
+    let magic: [u8; 4] = read_array(reader)?;
+    if &magic != b"fLaC" {
+        return Err(invalid("missing FLAC stream marker"));
+    }
+    loop {
+        let header: [u8; 4] = read_array(reader)?;
+        let last = header[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        match header[0] & 0x7F {
+            VORBIS_COMMENT => vorbis::parse_comments(&read_block(reader, len)?, tags)?,
+            _ => {
+                reader.seek(SeekFrom::Current(len as i64))?;
+            }
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{latin1, push_text, read_array, Tag, TagKey};
+
+pub const TAG_LEN: u64 = 128;
+
+/// The ID3v1 genre list, including the Winamp extensions
+const GENRES: [&str; 126] = [
+    "Blues",
+    "Classic Rock",
+    "Country",
+    "Dance",
+    "Disco",
+    "Funk",
+    "Grunge",
+    "Hip-Hop",
+    "Jazz",
+    "Metal",
+    "New Age",
+    "Oldies",
+    "Other",
+    "Pop",
+    "R&B",
+    "Rap",
+    "Reggae",
+    "Rock",
+    "Techno",
+    "Industrial",
+    "Alternative",
+    "Ska",
+    "Death Metal",
+    "Pranks",
+    "Soundtrack",
+    "Euro-Techno",
+    "Ambient",
+    "Trip-Hop",
+    "Vocal",
+    "Jazz+Funk",
+    "Fusion",
+    "Trance",
+    "Classical",
+    "Instrumental",
+    "Acid",
+    "House",
+    "Game",
+    "Sound Clip",
+    "Gospel",
+    "Noise",
+    "AlternRock",
+    "Bass",
+    "Soul",
+    "Punk",
+    "Space",
+    "Meditative",
+    "Instrumental Pop",
+    "Instrumental Rock",
+    "Ethnic",
+    "Gothic",
+    "Darkwave",
+    "Techno-Industrial",
+    "Electronic",
+    "Pop-Folk",
+    "Eurodance",
+    "Dream",
+    "Southern Rock",
+    "Comedy",
+    "Cult",
+    "Gangsta",
+    "Top 40",
+    "Christian Rap",
+    "Pop/Funk",
+    "Jungle",
+    "Native American",
+    "Cabaret",
+    "New Wave",
+    "Psychadelic",
+    "Rave",
+    "Showtunes",
+    "Trailer",
+    "Lo-Fi",
+    "Tribal",
+    "Acid Punk",
+    "Acid Jazz",
+    "Polka",
+    "Retro",
+    "Musical",
+    "Rock & Roll",
+    "Hard Rock",
+    "Folk",
+    "Folk-Rock",
+    "National Folk",
+    "Swing",
+    "Fast Fusion",
+    "Bebob",
+    "Latin",
+    "Revival",
+    "Celtic",
+    "Bluegrass",
+    "Avantgarde",
+    "Gothic Rock",
+    "Progressive Rock",
+    "Psychedelic Rock",
+    "Symphonic Rock",
+    "Slow Rock",
+    "Big Band",
+    "Chorus",
+    "Easy Listening",
+    "Acoustic",
+    "Humour",
+    "Speech",
+    "Chanson",
+    "Opera",
+    "Chamber Music",
+    "Sonata",
+    "Symphony",
+    "Booty Bass",
+    "Primus",
+    "Porn Groove",
+    "Satire",
+    "Slow Jam",
+    "Club",
+    "Tango",
+    "Samba",
+    "Folklore",
+    "Ballad",
+    "Power Ballad",
+    "Rhythmic Soul",
+    "Freestyle",
+    "Duet",
+    "Punk Rock",
+    "Drum Solo",
+    "A capella",
+    "Euro-House",
+    "Dance Hall",
+];
+
+pub fn genre(index: u8) -> Option<&'static str> {
+    GENRES.get(index as usize).copied()
+}
+
+/// Reads the ID3v1 tag in the last 128 bytes of the file, returning whether there was one
+pub fn read<R: Read + Seek>(reader: &mut R, len: u64, tags: &mut Vec<Tag>) -> io::Result<bool> {
+    if len < TAG_LEN {
+        return Ok(false);
+    }
+    reader.seek(SeekFrom::Start(len - TAG_LEN))?;
+    let block: [u8; TAG_LEN as usize] = read_array(reader)?;
+    if &block[..3] != b"TAG" {
+        return Ok(false);
+    }
+
+    push_text(tags, TagKey::Title, &latin1(trim_field(&block[3..33])));
+    push_text(tags, TagKey::Artist, &latin1(trim_field(&block[33..63])));
+    push_text(tags, TagKey::Album, &latin1(trim_field(&block[63..93])));
+    // ID3v1.1 steals the last two bytes of the comment for the track number
+    if block[125] == 0 && block[126] != 0 {
+        push_text(tags, TagKey::TrackNumber, &block[126].to_string());
+    }
+    if let Some(genre) = genre(block[127]) {
+        push_text(tags, TagKey::Genre, genre);
+    }
+    Ok(true)
+}
+
+/// Fields are padded with nulls or spaces, the spaces are trimmed along with the text
+fn trim_field(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
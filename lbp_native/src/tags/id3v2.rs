@@ -0,0 +1,272 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use super::{id3v1, invalid, latin1, picard, push_text, read_array, read_block, Tag, TagKey};
+
+/// Reads the ID3v2 tag at the current position, returning where the audio after it starts
+pub fn read<R: Read + Seek>(reader: &mut R, tags: &mut Vec<Tag>) -> io::Result<u64> {
+    let start = reader.stream_position()?;
+    let header: [u8; 10] = read_array(reader)?;
+    if &header[..3] != b"ID3" {
+        return Err(invalid("missing ID3v2 header"));
+    }
+    let major = header[3];
+    let flags = header[5];
+    let size = syncsafe(&header[6..10]);
+    let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+    let end = start + 10 + size + footer;
+
+    if (2..=4).contains(&major) {
+        if flags & 0x80 != 0 && major < 4 {
+            // Before v2.4, unsynchronisation applies to the tag as a whole
+            let body = unsynchronise(&read_block(reader, size)?);
+            let len = body.len() as u64;
+            read_frames(&mut Cursor::new(body), major, flags, len, tags)?;
+        } else {
+            read_frames(reader, major, flags, size, tags)?;
+        }
+    } else {
+        log::warn!("Unsupported ID3v2.{} tag", major);
+    }
+
+    reader.seek(SeekFrom::Start(end))?;
+    Ok(end)
+}
+
+fn read_frames<R: Read + Seek>(
+    reader: &mut R,
+    major: u8,
+    flags: u8,
+    len: u64,
+    tags: &mut Vec<Tag>,
+) -> io::Result<()> {
+    let mut pos = 0;
+
+    if flags & 0x40 != 0 && major >= 3 {
+        let ext: [u8; 4] = read_array(reader)?;
+        // v2.4 counts the size bytes themselves, v2.3 doesn't
+        let skip = if major == 4 {
+            syncsafe(&ext).saturating_sub(4)
+        } else {
+            u32::from_be_bytes(ext) as u64
+        };
+        reader.seek(SeekFrom::Current(skip as i64))?;
+        pos += 4 + skip;
+    }
+
+    let header_len = if major == 2 { 6 } else { 10 };
+    while pos + header_len <= len {
+        let mut header = [0; 10];
+        reader.read_exact(&mut header[..header_len as usize])?;
+        pos += header_len;
+        if header[0] == 0 {
+            // Padding
+            break;
+        }
+
+        let (id, size, format_flags) = if major == 2 {
+            let size = u32::from_be_bytes([0, header[3], header[4], header[5]]) as u64;
+            (v22_frame_id(&header[..3]), size, 0)
+        } else {
+            let size = if major == 4 {
+                syncsafe(&header[4..8])
+            } else {
+                u32::from_be_bytes(header[4..8].try_into().unwrap()) as u64
+            };
+            (Some(header[..4].try_into().unwrap()), size, header[9])
+        };
+        if pos + size > len {
+            break;
+        }
+        pos += size;
+
+        let Some(id) = id.filter(is_wanted) else {
+            reader.seek(SeekFrom::Current(size as i64))?;
+            continue;
+        };
+        let (compressed, encrypted) = match major {
+            4 => (format_flags & 0x08 != 0, format_flags & 0x04 != 0),
+            3 => (format_flags & 0x80 != 0, format_flags & 0x40 != 0),
+            _ => (false, false),
+        };
+        if compressed || encrypted {
+            reader.seek(SeekFrom::Current(size as i64))?;
+            continue;
+        }
+
+        let mut body = read_block(reader, size)?;
+        match major {
+            4 => {
+                let mut skip = 0;
+                if format_flags & 0x40 != 0 {
+                    // Grouping identity
+                    skip += 1;
+                }
+                if format_flags & 0x01 != 0 {
+                    // Data length indicator
+                    skip += 4;
+                }
+                body.drain(..skip.min(body.len()));
+                if format_flags & 0x02 != 0 {
+                    body = unsynchronise(&body);
+                }
+            }
+            3 if format_flags & 0x20 != 0 => {
+                body.drain(..1.min(body.len()));
+            }
+            _ => {}
+        }
+        read_frame(&id, &body, tags);
+    }
+    Ok(())
+}
+
+fn is_wanted(id: &[u8; 4]) -> bool {
+    matches!(
+        id,
+        b"TIT2"
+            | b"TPE1"
+            | b"TALB"
+            | b"TPE2"
+            | b"TRCK"
+            | b"TPOS"
+            | b"TSRC"
+            | b"TCON"
+            | b"TXXX"
+            | b"UFID"
+    )
+}
+
+/// Maps the three character frame IDs of ID3v2.2 to their v2.3 equivalents
+fn v22_frame_id(id: &[u8]) -> Option<[u8; 4]> {
+    Some(*match id {
+        b"TT2" => b"TIT2",
+        b"TP1" => b"TPE1",
+        b"TAL" => b"TALB",
+        b"TP2" => b"TPE2",
+        b"TRK" => b"TRCK",
+        b"TPA" => b"TPOS",
+        b"TRC" => b"TSRC",
+        b"TCO" => b"TCON",
+        b"TXX" => b"TXXX",
+        b"UFI" => b"UFID",
+        _ => return None,
+    })
+}
+
+fn read_frame(id: &[u8; 4], body: &[u8], tags: &mut Vec<Tag>) {
+    let key = match id {
+        b"TIT2" => TagKey::Title,
+        b"TPE1" => TagKey::Artist,
+        b"TALB" => TagKey::Album,
+        b"TPE2" => TagKey::AlbumArtist,
+        b"TRCK" => TagKey::TrackNumber,
+        b"TPOS" => TagKey::DiscNumber,
+        b"TSRC" => TagKey::Isrc,
+        b"TCON" => {
+            for genre in decode_text_list(body) {
+                push_text(tags, TagKey::Genre, &resolve_genre(&genre));
+            }
+            return;
+        }
+        b"TXXX" => {
+            let mut values = decode_text_list(body).into_iter();
+            let Some(key) = values.next().and_then(|desc| picard::id3_txxx(&desc)) else {
+                return;
+            };
+            for value in values {
+                push_text(tags, key, &value);
+            }
+            return;
+        }
+        b"UFID" => {
+            // A Latin-1 owner, then the identifier as raw bytes
+            let Some(split) = body.iter().position(|&b| b == 0) else {
+                return;
+            };
+            if let Some(key) = picard::id3_ufid(&latin1(&body[..split])) {
+                push_text(tags, key, &latin1(&body[split + 1..]));
+            }
+            return;
+        }
+        _ => return,
+    };
+    for value in decode_text_list(body) {
+        push_text(tags, key, &value);
+    }
+}
+
+/// Resolves ID3v1 genre references like "(17)", "(17)Rock" or "17"
+fn resolve_genre(genre: &str) -> String {
+    let (index, rest) = match genre.strip_prefix('(').and_then(|g| g.split_once(')')) {
+        Some((index, rest)) => (index, rest),
+        None => (genre, ""),
+    };
+    if !rest.is_empty() {
+        return rest.to_string();
+    }
+    match index.parse::<u8>().ok().and_then(id3v1::genre) {
+        Some(name) => name.to_string(),
+        None => genre.to_string(),
+    }
+}
+
+/// Decodes the null separated strings of a text frame, the first byte being the encoding
+fn decode_text_list(body: &[u8]) -> Vec<String> {
+    let Some((&encoding, data)) = body.split_first() else {
+        return Vec::new();
+    };
+    match encoding {
+        0 => data.split(|&b| b == 0).map(latin1).collect(),
+        3 => data
+            .split(|&b| b == 0)
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect(),
+        1 | 2 => {
+            let units: Vec<u16> = data
+                .chunks_exact(2)
+                .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                .collect();
+            units
+                .split(|&unit| unit == 0)
+                .map(|s| decode_utf16(s, encoding == 2))
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// `units` were read big endian. Encoding 1 strings start with a byte order mark saying
+/// whether that was right.
+fn decode_utf16(units: &[u16], big_endian: bool) -> String {
+    let (units, swap) = match units {
+        [0xFEFF, rest @ ..] => (rest, false),
+        [0xFFFE, rest @ ..] => (rest, true),
+        _ => (units, !big_endian),
+    };
+    char::decode_utf16(
+        units
+            .iter()
+            .map(|&unit| if swap { unit.swap_bytes() } else { unit }),
+    )
+    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    .collect()
+}
+
+fn syncsafe(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |size, &b| (size << 7) | (b & 0x7F) as u64)
+}
+
+/// Undoes unsynchronisation, which inserts a zero after every 0xFF
+fn unsynchronise(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut last = 0;
+    for &b in data {
+        if !(last == 0xFF && b == 0) {
+            out.push(b);
+        }
+        last = b;
+    }
+    out
+}
@@ -0,0 +1,99 @@
+//! The lofty backend, which covers more tag formats than symphonia without needing a git
+//! dependency
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    time::Duration,
+};
+
+use lofty::{
+    file::{AudioFile, TaggedFileExt},
+    probe::Probe,
+    tag::{ItemKey, ItemValue},
+};
+
+use super::{is_cue_sheet_key, Tag, TagKey, TagReader};
+use crate::tag_value::{clean_text, decode_bytes};
+
+pub struct Lofty;
+
+impl TagReader for Lofty {
+    fn name(&self) -> &'static str {
+        "lofty"
+    }
+
+    fn read_duration(&self, file: &File, _ext: &str) -> io::Result<Option<Duration>> {
+        let probe = Probe::new(BufReader::new(file)).guess_file_type()?;
+        if probe.file_type().is_none() {
+            return Ok(None);
+        }
+        let tagged_file = probe
+            .read()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let duration = tagged_file.properties().duration();
+        Ok((!duration.is_zero()).then_some(duration))
+    }
+
+    fn read_tags(&self, file: &File, _ext: &str) -> io::Result<Option<Vec<Tag>>> {
+        let probe = Probe::new(BufReader::new(file)).guess_file_type()?;
+        if probe.file_type().is_none() {
+            return Ok(None);
+        }
+        let tagged_file = probe
+            .read()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Files can carry several tags (ID3v2 and ID3v1, say), the primary one is the most
+        // complete
+        let Some(tag) = tagged_file.primary_tag().or(tagged_file.first_tag()) else {
+            return Ok(Some(Vec::new()));
+        };
+        let mut tags = Vec::new();
+        for item in tag.items() {
+            if let (ItemKey::Unknown(key), ItemValue::Text(value)) = (item.key(), item.value()) {
+                if is_cue_sheet_key(key) {
+                    tags.push(Tag {
+                        key: TagKey::CueSheet,
+                        value: value.clone(),
+                    });
+                    continue;
+                }
+            }
+            let Some(key) = tag_key(item.key()) else {
+                continue;
+            };
+            let value = match item.value() {
+                ItemValue::Text(text) | ItemValue::Locator(text) => clean_text(text),
+                ItemValue::Binary(bytes) => decode_bytes(bytes),
+            };
+            match value {
+                Some(value) => tags.push(Tag { key, value }),
+                None => log::warn!("Skipping undecodable value of tag {:?}", item.key()),
+            }
+        }
+        Ok(Some(tags))
+    }
+}
+
+fn tag_key(key: &ItemKey) -> Option<TagKey> {
+    Some(match key {
+        ItemKey::TrackTitle => TagKey::Title,
+        ItemKey::TrackArtist => TagKey::Artist,
+        ItemKey::TrackArtists => TagKey::Artists,
+        ItemKey::AlbumTitle => TagKey::Album,
+        ItemKey::AlbumArtist => TagKey::AlbumArtist,
+        ItemKey::TrackNumber => TagKey::TrackNumber,
+        ItemKey::DiscNumber => TagKey::DiscNumber,
+        ItemKey::Isrc => TagKey::Isrc,
+        ItemKey::Genre => TagKey::Genre,
+        // Unlike symphonia, lofty names these after what they identify
+        ItemKey::MusicBrainzRecordingId => TagKey::RecordingMbid,
+        ItemKey::MusicBrainzTrackId => TagKey::TrackMbid,
+        ItemKey::MusicBrainzReleaseId => TagKey::ReleaseMbid,
+        ItemKey::MusicBrainzArtistId => TagKey::ArtistMbid,
+        ItemKey::MusicBrainzReleaseGroupId => TagKey::ReleaseGroupMbid,
+        ItemKey::MusicBrainzWorkId => TagKey::WorkMbid,
+        _ => return None,
+    })
+}
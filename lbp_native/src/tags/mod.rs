@@ -0,0 +1,431 @@
+//! Reads tags straight out of the tag blocks of a file, without probing or demuxing any audio.
+//! Containers this doesn't know about are left to whichever full tag library backend the crate
+//! was built with.
+
+mod ape;
+mod dsd;
+#[cfg(test)]
+mod fixtures;
+mod flac;
+mod id3v1;
+mod id3v2;
+#[cfg(feature = "lofty")]
+pub mod lofty;
+mod mp4;
+mod ogg;
+pub(crate) mod picard;
+#[cfg(feature = "symphonia")]
+pub mod symphonia;
+mod vorbis;
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use crate::tag_value::clean_text;
+
+/// Refuse to buffer tag blocks larger than this, whatever a corrupt header claims
+const MAX_BLOCK_LEN: u64 = 16 * 1024 * 1024;
+
+/// The tags we submit, independent of how each container spells them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagKey {
+    Title,
+    Artist,
+    /// Picard's multi-valued list of the individual credited artists
+    Artists,
+    Album,
+    AlbumArtist,
+    TrackNumber,
+    DiscNumber,
+    Isrc,
+    Genre,
+    RecordingMbid,
+    /// The MBID of the track on a specific release, not the recording
+    TrackMbid,
+    ReleaseMbid,
+    ArtistMbid,
+    ReleaseGroupMbid,
+    WorkMbid,
+    /// A whole CUE sheet embedded in a single-file album, kept verbatim
+    CueSheet,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub key: TagKey,
+    pub value: String,
+}
+
+/// A way of reading the tags of a file
+pub trait TagReader {
+    fn name(&self) -> &'static str;
+
+    /// Returns `Ok(None)` when the reader doesn't support the file's container
+    fn read_tags(&self, file: &File, ext: &str) -> io::Result<Option<Vec<Tag>>>;
+
+    /// The length of the audio, for readers that parse more than the tags. Returns `Ok(None)`
+    /// when the reader doesn't support the file or it doesn't say.
+    fn read_duration(&self, _file: &File, _ext: &str) -> io::Result<Option<Duration>> {
+        Ok(None)
+    }
+}
+
+/// Readers in the order they're tried. The tag block readers are by far the cheapest, the
+/// backends catch the containers they don't handle.
+const READERS: &[&dyn TagReader] = &[
+    &TagBlocks,
+    #[cfg(feature = "lofty")]
+    &lofty::Lofty,
+    #[cfg(feature = "symphonia")]
+    &symphonia::Symphonia,
+];
+
+/// Reads the tags of a file with the first reader that supports it
+pub fn read_file(mut file: &File, ext: &str) -> Vec<Tag> {
+    for reader in READERS {
+        if let Err(e) = file.rewind() {
+            log::error!("Failed to rewind file: {}", e);
+            break;
+        }
+        match reader.read_tags(file, ext) {
+            Ok(Some(tags)) => return tags,
+            Ok(None) => log::debug!("{} doesn't support this file", reader.name()),
+            Err(e) => log::warn!("{} failed to read tags: {}", reader.name(), e),
+        }
+    }
+    Vec::new()
+}
+
+/// Reads the length of the audio with the first reader that knows it
+pub fn read_duration(mut file: &File, ext: &str) -> Option<Duration> {
+    for reader in READERS {
+        if let Err(e) = file.rewind() {
+            log::error!("Failed to rewind file: {}", e);
+            break;
+        }
+        match reader.read_duration(file, ext) {
+            Ok(Some(duration)) => return Some(duration),
+            Ok(None) => {}
+            Err(e) => log::warn!("{} failed to read the duration: {}", reader.name(), e),
+        }
+    }
+    None
+}
+
+pub struct TagBlocks;
+
+impl TagReader for TagBlocks {
+    fn name(&self) -> &'static str {
+        "tag blocks"
+    }
+
+    fn read_tags(&self, file: &File, _ext: &str) -> io::Result<Option<Vec<Tag>>> {
+        read_tags(&mut BufReader::new(file))
+    }
+}
+
+/// Reads the tags of a file using only its tag blocks.
+///
+/// Returns `Ok(None)` when the container isn't one we can read tags from, in which case the
+/// caller should fall back to a full probe.
+pub fn read_tags<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Vec<Tag>>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut tags = Vec::new();
+    let mut offset = 0;
+    let mut magic = read_magic(reader)?;
+    if magic.starts_with(b"ID3") {
+        reader.seek(SeekFrom::Start(0))?;
+        offset = id3v2::read(reader, &mut tags)?;
+        if offset > len {
+            return Err(invalid("ID3v2 tag overruns the file"));
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        magic = read_magic(reader)?;
+    }
+    reader.seek(SeekFrom::Start(offset))?;
+
+    if magic.starts_with(b"fLaC") {
+        flac::read(reader, &mut tags)?;
+    } else if magic.starts_with(b"OggS") {
+        ogg::read(reader, &mut tags)?;
+    } else if magic.get(4..8) == Some(b"ftyp") {
+        mp4::read(reader, len, &mut tags)?;
+    } else if magic.starts_with(b"DSD ") {
+        dsd::read_dsf(reader, &mut tags)?;
+    } else if magic.starts_with(b"FRM8") {
+        dsd::read_dff(reader, len, &mut tags)?;
+    } else if offset > 0 || is_mpeg_sync(&magic) || has_trailing_tags(&magic) {
+        // Raw MPEG audio, WavPack, Monkey's Audio and Musepack may carry APEv2 or ID3v1 tags at
+        // the end instead
+        if tags.is_empty() {
+            read_trailing_tags(reader, len, &mut tags)?;
+        }
+    } else if !read_trailing_tags(reader, len, &mut tags)? {
+        return Ok(None);
+    }
+
+    Ok(Some(tags))
+}
+
+/// Reads APEv2 or ID3v1 tags from the end of the file, preferring APEv2 since ID3v1 truncates
+/// every field. Returns whether either was found.
+fn read_trailing_tags<R: Read + Seek>(
+    reader: &mut R,
+    len: u64,
+    tags: &mut Vec<Tag>,
+) -> io::Result<bool> {
+    let mut id3v1_tags = Vec::new();
+    let has_id3v1 = id3v1::read(reader, len, &mut id3v1_tags)?;
+    let ape_end = if has_id3v1 { len - id3v1::TAG_LEN } else { len };
+    if ape::read(reader, ape_end, tags)? {
+        return Ok(true);
+    }
+    tags.append(&mut id3v1_tags);
+    Ok(has_id3v1)
+}
+
+/// WavPack, Monkey's Audio and Musepack (SV8 and SV7), which only use trailing tags
+fn has_trailing_tags(magic: &[u8]) -> bool {
+    [b"wvpk".as_slice(), b"MAC ", b"MPCK", b"MP+"]
+        .iter()
+        .any(|m| magic.starts_with(m))
+}
+
+fn is_mpeg_sync(magic: &[u8]) -> bool {
+    matches!(magic, [0xFF, b, ..] if b & 0xE0 == 0xE0)
+}
+
+fn read_magic<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut magic = Vec::with_capacity(12);
+    reader.take(12).read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_array<const N: usize, R: Read>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_block<R: Read>(reader: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    if len > MAX_BLOCK_LEN {
+        return Err(invalid("tag block too large"));
+    }
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Takes `len` bytes of `data` at `pos`, advancing `pos` past them
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> io::Result<&'a [u8]> {
+    let end = pos
+        .checked_add(len)
+        .filter(|end| *end <= data.len())
+        .ok_or_else(|| invalid("tag block truncated"))?;
+    let slice = &data[*pos..end];
+    *pos = end;
+    Ok(slice)
+}
+
+fn take_u32_le(data: &[u8], pos: &mut usize) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(take(data, pos, 4)?.try_into().unwrap()))
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Rippers embed the CUE sheet of a single-file album in a text tag of this name
+fn is_cue_sheet_key(key: &str) -> bool {
+    key.eq_ignore_ascii_case("CUESHEET")
+}
+
+fn push_text(tags: &mut Vec<Tag>, key: TagKey, text: &str) {
+    if let Some(value) = clean_text(text) {
+        tags.push(Tag { key, value });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const RECORDING: &str = "5d5b6e4e-59f5-4d5b-8ce1-2d1a8a30d5f4";
+    const RELEASE: &str = "0b3c9e6e-8d6f-4e8b-9a37-3e4f8d1c2b7a";
+
+    fn tag(key: TagKey, value: &str) -> Tag {
+        Tag {
+            key,
+            value: value.to_string(),
+        }
+    }
+
+    fn read(data: &[u8]) -> io::Result<Option<Vec<Tag>>> {
+        read_tags(&mut Cursor::new(data))
+    }
+
+    fn picard_tags() -> Vec<Tag> {
+        vec![
+            tag(TagKey::Title, "Fixture Title"),
+            tag(TagKey::Artist, "First Artist & Second Artist"),
+            tag(TagKey::Artists, "First Artist"),
+            tag(TagKey::Artists, "Second Artist"),
+            tag(TagKey::Album, "Fixture Album"),
+            tag(TagKey::TrackNumber, "3"),
+            tag(TagKey::RecordingMbid, RECORDING),
+            tag(TagKey::ReleaseMbid, RELEASE),
+        ]
+    }
+
+    #[test]
+    fn reads_every_fixture() {
+        for (name, data) in fixtures::all() {
+            let expected = if name.ends_with(".m4a") {
+                vec![
+                    tag(TagKey::Title, "Fixture Title"),
+                    tag(TagKey::Artist, "First Artist & Second Artist"),
+                    tag(TagKey::Album, "Fixture Album"),
+                    tag(TagKey::Artists, "First Artist"),
+                    tag(TagKey::Artists, "Second Artist"),
+                    tag(TagKey::ReleaseMbid, RELEASE),
+                ]
+            } else {
+                picard_tags()
+            };
+            assert_eq!(read(&data).unwrap(), Some(expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn reads_id3v1() {
+        let mut data = vec![0xFF, 0xFB, 0x90, 0x64];
+        data.resize(4096, 0);
+        let mut block = b"TAG".to_vec();
+        for field in ["Fixture Title", "Fixture Artist", "Fixture Album"] {
+            let mut field = field.as_bytes().to_vec();
+            field.resize(30, 0);
+            block.extend(field);
+        }
+        block.resize(125, 0);
+        block.extend([0, 3, 17]);
+        data.extend(block);
+
+        let expected = vec![
+            tag(TagKey::Title, "Fixture Title"),
+            tag(TagKey::Artist, "Fixture Artist"),
+            tag(TagKey::Album, "Fixture Album"),
+            tag(TagKey::TrackNumber, "3"),
+            tag(TagKey::Genre, "Rock"),
+        ];
+        assert_eq!(read(&data).unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn reads_dff() {
+        let tag_chunk = fixtures::id3v2(4);
+        let mut data = b"FRM8".to_vec();
+        data.extend_from_slice(&0u64.to_be_bytes());
+        data.extend_from_slice(b"DSD ");
+        // An odd length chunk, padded to an even one
+        data.extend_from_slice(b"COMT");
+        data.extend_from_slice(&3u64.to_be_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(b"ID3 ");
+        data.extend_from_slice(&(tag_chunk.len() as u64).to_be_bytes());
+        data.extend(tag_chunk);
+
+        assert_eq!(read(&data).unwrap(), Some(picard_tags()));
+    }
+
+    #[test]
+    fn unknown_containers_are_left_to_the_backends() {
+        assert_eq!(read(b"RIFF\0\0\0\0WAVEfmt ").unwrap(), None);
+        assert_eq!(read(b"").unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_blocks_are_errors() {
+        for (_, data) in fixtures::all() {
+            // Cutting anywhere must never panic, whatever it returns
+            for cut in (0..data.len()).step_by(97) {
+                let _ = read(&data[..cut]);
+            }
+        }
+
+        let flac = fixtures::flac();
+        assert!(read(&flac[..100]).is_err());
+        let ogg = fixtures::ogg();
+        assert!(read(&ogg[..ogg.len() - 10]).is_err());
+        for major in [3, 4] {
+            let mp3 = fixtures::mp3(major);
+            assert!(read(&mp3[..200]).is_err());
+        }
+        let dsf = fixtures::dsf();
+        assert!(read(&dsf[..28 + 256 * 1024 + 200]).is_err());
+        let m4a = fixtures::m4a();
+        assert!(read(&m4a[..m4a.len() - 100]).is_err());
+        let wavpack = fixtures::wavpack();
+        // Without its footer the APEv2 tag just isn't there, but a footer claiming more items
+        // than the file holds is an error
+        assert_eq!(
+            read(&wavpack[..wavpack.len() - 10]).unwrap(),
+            Some(Vec::new())
+        );
+        let mut cut = b"wvpk".to_vec();
+        cut.extend_from_slice(&wavpack[wavpack.len() - 64..]);
+        assert!(read(&cut).is_err());
+    }
+
+    #[test]
+    fn oversized_blocks_are_errors() {
+        let mut mp3 = fixtures::mp3(4);
+        mp3[6..10].copy_from_slice(&[0x7F; 4]);
+        assert!(read(&mp3).is_err());
+
+        let mut flac = fixtures::flac();
+        let comments = 4 + 4 + 34;
+        flac[comments + 1..comments + 4].copy_from_slice(&[0xFF; 3]);
+        assert!(read(&flac).is_err());
+
+        let mut vorbis = fixtures::vorbis_comments();
+        vorbis[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(vorbis::parse_comments(&vorbis, &mut Vec::new()).is_err());
+
+        let mut ogg = fixtures::ogg();
+        let second_page = ogg[4..].windows(4).position(|w| w == b"OggS").unwrap() + 4;
+        ogg[second_page + 27..].fill(0xFF);
+        assert!(read(&ogg).is_err());
+
+        let mut m4a = fixtures::m4a();
+        let moov = m4a.windows(4).position(|w| w == b"moov").unwrap() - 4;
+        m4a[moov..moov + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read(&m4a).is_err());
+
+        // A 64-bit size that would overflow the end of the atom
+        let mut m4a = fixtures::atom(b"ftyp", b"M4A \0\0\0\0M4A mp42isom");
+        m4a.extend_from_slice(&1u32.to_be_bytes());
+        m4a.extend_from_slice(b"moov");
+        m4a.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(read(&m4a).is_err());
+
+        let mut wavpack = fixtures::wavpack();
+        let size = wavpack.len() - 32 + 12;
+        wavpack[size..size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read(&wavpack).is_err());
+
+        let mut dsf = fixtures::dsf();
+        dsf[20..28].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        assert!(read(&dsf).is_err());
+    }
+}
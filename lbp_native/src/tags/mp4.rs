@@ -0,0 +1,197 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{id3v1, invalid, picard, push_text, read_array, read_block, take, Tag, TagKey};
+
+struct Atom {
+    body: u64,
+    end: u64,
+}
+
+/// Follows moov/udta/meta/ilst and reads the iTunes style metadata items. Skipping over
+/// `mdat` is a seek, so it doesn't matter which end of the file `moov` is at.
+pub fn read<R: Read + Seek>(reader: &mut R, len: u64, tags: &mut Vec<Tag>) -> io::Result<()> {
+    let Some(moov) = find_atom(reader, 0, len, b"moov")? else {
+        return Ok(());
+    };
+    let Some(udta) = find_atom(reader, moov.body, moov.end, b"udta")? else {
+        return Ok(());
+    };
+    let Some(meta) = find_atom(reader, udta.body, udta.end, b"meta")? else {
+        return Ok(());
+    };
+    // `meta` is a full atom in ISO files, but QuickTime writes it without version and flags
+    reader.seek(SeekFrom::Start(meta.body))?;
+    let peek: [u8; 8] = read_array(reader)?;
+    let meta_body = if &peek[4..8] == b"hdlr" {
+        meta.body
+    } else {
+        meta.body + 4
+    };
+    let Some(ilst) = find_atom(reader, meta_body, meta.end, b"ilst")? else {
+        return Ok(());
+    };
+
+    // Cover art usually dwarfs everything else in `ilst`, so only the items we map are read
+    let mut pos = ilst.body;
+    while pos + 8 <= ilst.end {
+        reader.seek(SeekFrom::Start(pos))?;
+        let header: [u8; 8] = read_array(reader)?;
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        if size < 8 || pos + size > ilst.end {
+            return Err(invalid("MP4 atom overruns its parent"));
+        }
+        if is_wanted(&kind) {
+            read_item(kind, &read_block(reader, size - 8)?, tags)?;
+        }
+        pos += size;
+    }
+    Ok(())
+}
+
+fn is_wanted(kind: &[u8; 4]) -> bool {
+    matches!(
+        kind,
+        b"\xA9nam"
+            | b"\xA9ART"
+            | b"\xA9alb"
+            | b"aART"
+            | b"\xA9gen"
+            | b"gnre"
+            | b"trkn"
+            | b"disk"
+            | b"----"
+    )
+}
+
+fn find_atom<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    kind: &[u8; 4],
+) -> io::Result<Option<Atom>> {
+    let mut pos = start;
+    while pos + 8 <= end {
+        reader.seek(SeekFrom::Start(pos))?;
+        let header: [u8; 8] = read_array(reader)?;
+        let atom_kind: [u8; 4] = header[4..8].try_into().unwrap();
+        let (header_len, size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (8, end - pos),
+            1 => (16, u64::from_be_bytes(read_array(reader)?)),
+            size => (8, size as u64),
+        };
+        let atom_end = pos
+            .checked_add(size)
+            .filter(|&atom_end| size >= header_len && atom_end <= end)
+            .ok_or_else(|| invalid("MP4 atom overruns its parent"))?;
+        if &atom_kind == kind {
+            return Ok(Some(Atom {
+                body: pos + header_len,
+                end: atom_end,
+            }));
+        }
+        pos = atom_end;
+    }
+    Ok(None)
+}
+
+/// Splits the next atom off an in-memory buffer, returning its type and body
+fn take_atom<'a>(data: &'a [u8], pos: &mut usize) -> io::Result<([u8; 4], &'a [u8])> {
+    let header = take(data, pos, 8)?;
+    let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let kind = header[4..8].try_into().unwrap();
+    if size < 8 {
+        return Err(invalid("MP4 atom too small"));
+    }
+    Ok((kind, take(data, pos, size - 8)?))
+}
+
+fn read_item(kind: [u8; 4], item: &[u8], tags: &mut Vec<Tag>) -> io::Result<()> {
+    let mut mean = None;
+    let mut name = None;
+    let mut values = Vec::new();
+    let mut pos = 0;
+    while pos < item.len() {
+        let (child, body) = take_atom(item, &mut pos)?;
+        match &child {
+            // Version and flags precede the text of both
+            b"mean" if body.len() >= 4 => mean = Some(String::from_utf8_lossy(&body[4..])),
+            b"name" if body.len() >= 4 => name = Some(String::from_utf8_lossy(&body[4..])),
+            // Type indicator and locale precede the payload
+            b"data" if body.len() >= 8 => {
+                let data_type = u32::from_be_bytes(body[..4].try_into().unwrap()) & 0xFF_FFFF;
+                values.push((data_type, &body[8..]));
+            }
+            _ => {}
+        }
+    }
+
+    let key = match &kind {
+        b"\xA9nam" => TagKey::Title,
+        b"\xA9ART" => TagKey::Artist,
+        b"\xA9alb" => TagKey::Album,
+        b"aART" => TagKey::AlbumArtist,
+        b"\xA9gen" => TagKey::Genre,
+        b"gnre" => {
+            for (_, payload) in values {
+                if let [hi, lo, ..] = *payload {
+                    let index = u16::from_be_bytes([hi, lo]);
+                    let genre = index.checked_sub(1).and_then(|i| u8::try_from(i).ok());
+                    if let Some(genre) = genre.and_then(id3v1::genre) {
+                        push_text(tags, TagKey::Genre, genre);
+                    }
+                }
+            }
+            return Ok(());
+        }
+        b"trkn" | b"disk" => {
+            let key = if &kind == b"trkn" {
+                TagKey::TrackNumber
+            } else {
+                TagKey::DiscNumber
+            };
+            for (_, payload) in values {
+                // Reserved, number, total
+                if let [_, _, hi, lo, ..] = *payload {
+                    let number = u16::from_be_bytes([hi, lo]);
+                    if number > 0 {
+                        push_text(tags, key, &number.to_string());
+                    }
+                }
+            }
+            return Ok(());
+        }
+        b"----" => {
+            let (Some(mean), Some(name)) = (mean, name) else {
+                return Ok(());
+            };
+            match picard::mp4_freeform(&mean, &name) {
+                Some(key) => key,
+                None => return Ok(()),
+            }
+        }
+        _ => return Ok(()),
+    };
+    for (data_type, payload) in values {
+        if let Some(text) = decode_text(data_type, payload) {
+            push_text(tags, key, &text);
+        }
+    }
+    Ok(())
+}
+
+/// Well-known data types 1 and 2 are UTF-8 and UTF-16, freeform items written by older
+/// taggers sometimes claim to be binary (0) but hold UTF-8 anyway
+fn decode_text(data_type: u32, payload: &[u8]) -> Option<String> {
+    match data_type {
+        0 | 1 => std::str::from_utf8(payload).ok().map(str::to_string),
+        2 => char::decode_utf16(
+            payload
+                .chunks_exact(2)
+                .map(|unit| u16::from_be_bytes([unit[0], unit[1]])),
+        )
+        .collect::<Result<String, _>>()
+        .ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,58 @@
+use std::io::{self, Read};
+
+use super::{invalid, read_array, read_block, vorbis, Tag, MAX_BLOCK_LEN};
+
+/// Reassembles the first two packets of the first logical stream, which hold the codec
+/// identification and its comments, and reads the comments
+pub fn read<R: Read>(reader: &mut R, tags: &mut Vec<Tag>) -> io::Result<()> {
+    let mut packets: Vec<Vec<u8>> = Vec::with_capacity(2);
+    let mut packet = Vec::new();
+    let mut serial = None;
+
+    'pages: while packets.len() < 2 {
+        let header: [u8; 27] = read_array(reader)?;
+        if &header[..4] != b"OggS" {
+            return Err(invalid("missing Ogg page capture pattern"));
+        }
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        let lacing = read_block(reader, header[26] as u64)?;
+        let body = read_block(reader, lacing.iter().map(|&l| l as u64).sum())?;
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+
+        let mut offset = 0;
+        for &lace in &lacing {
+            packet.extend_from_slice(&body[offset..offset + lace as usize]);
+            offset += lace as usize;
+            if lace < 255 {
+                packets.push(std::mem::take(&mut packet));
+                if packets.len() == 2 {
+                    break 'pages;
+                }
+            }
+        }
+        if packet.len() as u64 > MAX_BLOCK_LEN {
+            return Err(invalid("Ogg comment packet too large"));
+        }
+    }
+
+    let (ident, comment) = (&packets[0], &packets[1]);
+    if let Some(comments) = comment.strip_prefix(b"\x03vorbis") {
+        vorbis::parse_comments(comments, tags)
+    } else if let Some(comments) = comment.strip_prefix(b"OpusTags") {
+        vorbis::parse_comments(comments, tags)
+    } else if ident.starts_with(b"\x7FFLAC") {
+        // Ogg FLAC carries its metadata blocks as packets, header and all
+        match comment.split_first() {
+            Some((&block_type, block)) if block_type & 0x7F == 4 && block.len() >= 3 => {
+                vorbis::parse_comments(&block[3..], tags)
+            }
+            _ => Ok(()),
+        }
+    } else if ident.starts_with(b"Speex   ") {
+        vorbis::parse_comments(comment, tags)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+//! How Picard spells each tag we submit in every container it writes.
+//!
+//! See <https://picard-docs.musicbrainz.org/en/appendices/tag_mapping.html>. Lookups ignore
+//! case since other taggers don't always match Picard's.
+
+use super::TagKey;
+
+/// The owner of the UFID frame Picard stores the recording MBID in
+const UFID_OWNER: &str = "http://musicbrainz.org";
+
+/// ID3v2 `TXXX` descriptions
+pub fn id3_txxx(desc: &str) -> Option<TagKey> {
+    Some(match desc.to_ascii_uppercase().as_str() {
+        "ARTISTS" => TagKey::Artists,
+        "MUSICBRAINZ RELEASE TRACK ID" => TagKey::TrackMbid,
+        "MUSICBRAINZ ALBUM ID" => TagKey::ReleaseMbid,
+        "MUSICBRAINZ ARTIST ID" => TagKey::ArtistMbid,
+        "MUSICBRAINZ RELEASE GROUP ID" => TagKey::ReleaseGroupMbid,
+        "MUSICBRAINZ WORK ID" => TagKey::WorkMbid,
+        _ => return None,
+    })
+}
+
+/// ID3v2 `UFID` owners
+pub fn id3_ufid(owner: &str) -> Option<TagKey> {
+    owner
+        .eq_ignore_ascii_case(UFID_OWNER)
+        .then_some(TagKey::RecordingMbid)
+}
+
+/// MP4 freeform (`----`) atoms
+pub fn mp4_freeform(mean: &str, name: &str) -> Option<TagKey> {
+    if mean != "com.apple.iTunes" {
+        return None;
+    }
+    Some(match name.to_ascii_uppercase().as_str() {
+        "ARTISTS" => TagKey::Artists,
+        "ISRC" => TagKey::Isrc,
+        // Picard's "track id" is the recording, the release track has its own name
+        "MUSICBRAINZ TRACK ID" => TagKey::RecordingMbid,
+        "MUSICBRAINZ RELEASE TRACK ID" => TagKey::TrackMbid,
+        "MUSICBRAINZ ALBUM ID" => TagKey::ReleaseMbid,
+        "MUSICBRAINZ ARTIST ID" => TagKey::ArtistMbid,
+        "MUSICBRAINZ RELEASE GROUP ID" => TagKey::ReleaseGroupMbid,
+        "MUSICBRAINZ WORK ID" => TagKey::WorkMbid,
+        _ => return None,
+    })
+}
+
+/// Vorbis comment field names
+pub fn vorbis(key: &str) -> Option<TagKey> {
+    Some(match key.to_ascii_uppercase().as_str() {
+        "TITLE" => TagKey::Title,
+        "ARTIST" => TagKey::Artist,
+        "ARTISTS" => TagKey::Artists,
+        "ALBUM" => TagKey::Album,
+        // Picard reads the spaced spelling other taggers write too
+        "ALBUMARTIST" | "ALBUM ARTIST" => TagKey::AlbumArtist,
+        "TRACKNUMBER" => TagKey::TrackNumber,
+        "DISCNUMBER" => TagKey::DiscNumber,
+        "ISRC" => TagKey::Isrc,
+        "GENRE" => TagKey::Genre,
+        "MUSICBRAINZ_TRACKID" => TagKey::RecordingMbid,
+        "MUSICBRAINZ_RELEASETRACKID" => TagKey::TrackMbid,
+        "MUSICBRAINZ_ALBUMID" => TagKey::ReleaseMbid,
+        "MUSICBRAINZ_ARTISTID" => TagKey::ArtistMbid,
+        "MUSICBRAINZ_RELEASEGROUPID" => TagKey::ReleaseGroupMbid,
+        "MUSICBRAINZ_WORKID" => TagKey::WorkMbid,
+        _ => return None,
+    })
+}
+
+/// APEv2 item keys, which follow the Vorbis names apart from the numbering
+pub fn ape(key: &str) -> Option<TagKey> {
+    match key.to_ascii_uppercase().as_str() {
+        "TRACK" => Some(TagKey::TrackNumber),
+        "DISC" => Some(TagKey::DiscNumber),
+        _ => vorbis(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_the_musicbrainz_ufid() {
+        let cases = [
+            ("http://musicbrainz.org", Some(TagKey::RecordingMbid)),
+            ("HTTP://MusicBrainz.org", Some(TagKey::RecordingMbid)),
+            ("https://musicbrainz.org", None),
+            ("http://www.cddb.com/id3/taginfo1.html", None),
+            ("", None),
+        ];
+        for (owner, expected) in cases {
+            assert_eq!(id3_ufid(owner), expected, "{:?}", owner);
+        }
+    }
+
+    #[test]
+    fn maps_musicbrainz_txxx_ids() {
+        let cases = [
+            ("MusicBrainz Release Track Id", Some(TagKey::TrackMbid)),
+            ("MusicBrainz Album Id", Some(TagKey::ReleaseMbid)),
+            ("MusicBrainz Artist Id", Some(TagKey::ArtistMbid)),
+            (
+                "MusicBrainz Release Group Id",
+                Some(TagKey::ReleaseGroupMbid),
+            ),
+            ("MusicBrainz Work Id", Some(TagKey::WorkMbid)),
+            ("musicbrainz album id", Some(TagKey::ReleaseMbid)),
+            ("ARTISTS", Some(TagKey::Artists)),
+            // The recording MBID only ever lives in the UFID frame
+            ("MusicBrainz Track Id", None),
+            ("MusicBrainz Album Artist Id", None),
+            ("MusicBrainz Disc Id", None),
+            ("", None),
+        ];
+        for (desc, expected) in cases {
+            assert_eq!(id3_txxx(desc), expected, "{:?}", desc);
+        }
+    }
+
+    #[test]
+    fn maps_mp4_freeform_atoms() {
+        let itunes = "com.apple.iTunes";
+        let cases = [
+            (itunes, "MusicBrainz Track Id", Some(TagKey::RecordingMbid)),
+            (
+                itunes,
+                "MusicBrainz Release Track Id",
+                Some(TagKey::TrackMbid),
+            ),
+            (itunes, "MusicBrainz Album Id", Some(TagKey::ReleaseMbid)),
+            (itunes, "MusicBrainz Artist Id", Some(TagKey::ArtistMbid)),
+            (
+                itunes,
+                "MusicBrainz Release Group Id",
+                Some(TagKey::ReleaseGroupMbid),
+            ),
+            (itunes, "MusicBrainz Work Id", Some(TagKey::WorkMbid)),
+            (itunes, "MUSICBRAINZ TRACK ID", Some(TagKey::RecordingMbid)),
+            (itunes, "ARTISTS", Some(TagKey::Artists)),
+            (itunes, "ISRC", Some(TagKey::Isrc)),
+            (itunes, "MusicBrainz Album Artist Id", None),
+            // Only iTunes' namespace carries Picard's names
+            ("org.example.tagger", "MusicBrainz Track Id", None),
+            ("COM.APPLE.ITUNES", "MusicBrainz Track Id", None),
+        ];
+        for (mean, name, expected) in cases {
+            assert_eq!(mp4_freeform(mean, name), expected, "{}:{}", mean, name);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! The fallback for containers the tag block readers don't handle, which probes the whole file
+
+use std::{fs::File, io, time::Duration};
+
+use symphonia::core::{
+    errors::Error,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, StandardTagKey, Value},
+    probe::{Hint, ProbeResult},
+};
+
+use super::{is_cue_sheet_key, picard, Tag, TagKey, TagReader};
+use crate::tag_value::decode_value;
+
+pub struct Symphonia;
+
+impl TagReader for Symphonia {
+    fn name(&self) -> &'static str {
+        "symphonia"
+    }
+
+    fn read_tags(&self, file: &File, ext: &str) -> io::Result<Option<Vec<Tag>>> {
+        read_tags(file.try_clone()?, ext)
+    }
+
+    fn read_duration(&self, file: &File, ext: &str) -> io::Result<Option<Duration>> {
+        let Some(probed) = probe(file.try_clone()?, ext)? else {
+            return Ok(None);
+        };
+        let Some(track) = probed.format.default_track() else {
+            return Ok(None);
+        };
+        let params = &track.codec_params;
+        let Some(frames) = params.n_frames else {
+            return Ok(None);
+        };
+        // The time base is the container's, the sample rate only stands in when it has none
+        let duration = match (params.time_base, params.sample_rate) {
+            (Some(time_base), _) => {
+                let time = time_base.calc_time(frames);
+                Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+            }
+            (None, Some(sample_rate)) if sample_rate > 0 => {
+                Duration::from_millis(frames * 1000 / sample_rate as u64)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(duration))
+    }
+}
+
+pub(crate) fn probe(src: File, ext: &str) -> io::Result<Option<ProbeResult>> {
+    // Create the media source stream.
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+
+    // Create a probe hint using the file's extension. [Optional]
+    let mut hint = Hint::new();
+    hint.with_extension(ext);
+
+    // Use the default options for metadata and format readers.
+    let meta_opts: MetadataOptions = Default::default();
+    let fmt_opts: FormatOptions = Default::default();
+
+    // Probe the media source.
+    match symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts) {
+        Ok(probed) => Ok(Some(probed)),
+        Err(Error::Unsupported(_)) => Ok(None),
+        Err(Error::IoError(e)) => Err(e),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+fn read_tags(src: File, ext: &str) -> io::Result<Option<Vec<Tag>>> {
+    let Some(probed) = probe(src, ext)? else {
+        return Ok(None);
+    };
+
+    let mut probed_metadata_vec = Vec::new();
+    let mut metadata_vec = Vec::new();
+
+    let mut metadata = probed.metadata;
+    let mut format = probed.format;
+
+    if let Some(mut m) = metadata.get() {
+        if let Some(latest) = m.skip_to_latest() {
+            probed_metadata_vec = latest.tags().to_vec();
+        }
+    }
+
+    let mut metadata = format.metadata();
+
+    if let Some(latest) = metadata.skip_to_latest() {
+        metadata_vec = latest.tags().to_vec();
+    }
+
+    let mut tags = Vec::new();
+    for tag in probed_metadata_vec.drain(..).chain(metadata_vec.drain(..)) {
+        if is_cue_sheet_key(&tag.key) {
+            if let Value::String(value) = tag.value {
+                tags.push(Tag {
+                    key: TagKey::CueSheet,
+                    value,
+                });
+            }
+            continue;
+        }
+        let key = match tag.std_key {
+            Some(std_key) => tag_key(std_key),
+            None => raw_key(&tag.key),
+        };
+        let Some(key) = key else {
+            continue;
+        };
+        match decode_value(tag.value) {
+            Some(value) => tags.push(Tag { key, value }),
+            None => log::warn!("Skipping undecodable value of tag {}", tag.key),
+        }
+    }
+    Ok(Some(tags))
+}
+
+/// Symphonia only maps some of Picard's tags to standard keys (and its ID3v2 `TXXX` lookup is
+/// case sensitive), so the rest are recognized by their raw keys. It skips `UFID` frames
+/// entirely, so recording MBIDs in MP3s are only read by the tag block readers.
+fn raw_key(key: &str) -> Option<TagKey> {
+    if let Some(desc) = key.strip_prefix("TXXX:") {
+        picard::id3_txxx(desc)
+    } else if let Some((mean, name)) = key.split_once(':') {
+        picard::mp4_freeform(mean, name)
+    } else {
+        picard::vorbis(key)
+    }
+}
+
+fn tag_key(std_key: StandardTagKey) -> Option<TagKey> {
+    Some(match std_key {
+        StandardTagKey::TrackTitle => TagKey::Title,
+        StandardTagKey::Artist => TagKey::Artist,
+        StandardTagKey::Album => TagKey::Album,
+        StandardTagKey::AlbumArtist => TagKey::AlbumArtist,
+        StandardTagKey::TrackNumber => TagKey::TrackNumber,
+        StandardTagKey::DiscNumber => TagKey::DiscNumber,
+        StandardTagKey::IdentIsrc => TagKey::Isrc,
+        StandardTagKey::Genre => TagKey::Genre,
+        StandardTagKey::MusicBrainzRecordingId => TagKey::RecordingMbid,
+        // Symphonia names these after the Picard tags, whose "track id" is the recording
+        StandardTagKey::MusicBrainzTrackId => TagKey::RecordingMbid,
+        StandardTagKey::MusicBrainzReleaseTrackId => TagKey::TrackMbid,
+        StandardTagKey::MusicBrainzAlbumId => TagKey::ReleaseMbid,
+        StandardTagKey::MusicBrainzArtistId => TagKey::ArtistMbid,
+        StandardTagKey::MusicBrainzReleaseGroupId => TagKey::ReleaseGroupMbid,
+        StandardTagKey::MusicBrainzWorkId => TagKey::WorkMbid,
+        _ => return None,
+    })
+}
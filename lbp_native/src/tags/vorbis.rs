@@ -0,0 +1,31 @@
+use std::io;
+
+use super::{is_cue_sheet_key, picard, push_text, take, take_u32_le, Tag, TagKey};
+
+/// Parses a Vorbis comment block, as found in FLAC, Ogg Vorbis, Opus and Speex
+pub fn parse_comments(data: &[u8], tags: &mut Vec<Tag>) -> io::Result<()> {
+    let mut pos = 0;
+    let vendor_len = take_u32_le(data, &mut pos)? as usize;
+    take(data, &mut pos, vendor_len)?;
+    let count = take_u32_le(data, &mut pos)?;
+    for _ in 0..count {
+        let len = take_u32_le(data, &mut pos)? as usize;
+        let comment = take(data, &mut pos, len)?;
+        let Some(split) = comment.iter().position(|&b| b == b'=') else {
+            continue;
+        };
+        let (key, value) = (&comment[..split], &comment[split + 1..]);
+        let (Ok(key), Ok(value)) = (std::str::from_utf8(key), std::str::from_utf8(value)) else {
+            continue;
+        };
+        if is_cue_sheet_key(key) {
+            tags.push(Tag {
+                key: TagKey::CueSheet,
+                value: value.to_string(),
+            });
+        } else if let Some(key) = picard::vorbis(key) {
+            push_text(tags, key, value);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,407 @@
+//! Checks listens against ListenBrainz's listen schema before they're submitted. The server
+//! rejects a whole listen over one bad field, and a rejected listen stays rejected however often
+//! it's retried, so whatever can be repaired is, invalid optional fields are dropped, and only a
+//! listen missing what's required is refused.
+
+use std::fmt;
+
+use crate::{Payload, UUID_REGEX};
+
+/// Listens from before ListenBrainz's earliest accepted timestamp, in October 2002
+const LISTEN_MINIMUM_TS: u64 = 1033430400;
+/// Measured on the listen as Python's `json.dumps` writes it
+const MAX_LISTEN_SIZE: usize = 10240;
+const MAX_TAGS_PER_LISTEN: usize = 50;
+/// In characters
+const MAX_TAG_SIZE: usize = 64;
+const MAX_DURATION_MS: u64 = 24 * 24 * 60 * 60 * 1000;
+
+/// Something validation changed about a listen
+#[derive(Debug, PartialEq, Eq)]
+pub enum Repair {
+    Cleaned(&'static str),
+    /// The MBID only needed its case or surrounding whitespace fixed
+    NormalizedMbid(&'static str, String),
+    /// The MBID was found inside what the field held
+    ExtractedMbid(&'static str, String),
+    Dropped(&'static str, String),
+    /// Dropped to fit the listen into the size limit
+    DroppedForSize(&'static str),
+    /// This many tags past the most a listen may have
+    DroppedTags(usize),
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cleaned(field) => write!(f, "cleaned up {}", field),
+            Self::NormalizedMbid(field, value) => write!(f, "normalized {} {:?}", field, value),
+            Self::ExtractedMbid(field, value) => {
+                write!(f, "extracted the MBID from {} {:?}", field, value)
+            }
+            Self::Dropped(field, value) => write!(f, "dropped invalid {} {:?}", field, value),
+            Self::DroppedForSize(field) => write!(f, "dropped {} to fit the size limit", field),
+            Self::DroppedTags(count) => write!(
+                f,
+                "dropped {} tags past the first {}",
+                count, MAX_TAGS_PER_LISTEN
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    Missing(&'static str),
+    ListenedAt(Option<u64>),
+    TooLarge(usize),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(field) => write!(f, "missing {}", field),
+            Self::ListenedAt(Some(listened_at)) => {
+                write!(f, "listened_at {} is too early", listened_at)
+            }
+            Self::ListenedAt(None) => write!(f, "missing listened_at"),
+            Self::TooLarge(size) => write!(
+                f,
+                "listen is {} bytes, more than {} even without its optional fields",
+                size, MAX_LISTEN_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Repairs the payload for a listen of `listen_type`, returning what was changed. Fails when
+/// the server would reject it whatever was done about it.
+pub fn validate(listen_type: &str, payload: &mut Payload) -> Result<Vec<Repair>, ValidationError> {
+    let mut repairs = Vec::new();
+
+    if listen_type == "playing_now" {
+        if let Some(listened_at) = payload.listened_at.take() {
+            repairs.push(Repair::Dropped("listened_at", listened_at.to_string()));
+        }
+    } else {
+        match payload.listened_at.map(|listened_at| listened_at.get()) {
+            Some(listened_at) if listened_at >= LISTEN_MINIMUM_TS => {}
+            listened_at => return Err(ValidationError::ListenedAt(listened_at)),
+        }
+    }
+
+    let track_metadata = &mut payload.track_metadata;
+    let info = &mut track_metadata.additional_info;
+    for (field, value) in [
+        ("artist_name", &mut track_metadata.artist_name),
+        ("track_name", &mut track_metadata.track_name),
+        ("release_name", &mut track_metadata.release_name),
+        ("release_artist_name", &mut info.release_artist_name),
+        ("tracknumber", &mut info.tracknumber),
+        ("discnumber", &mut info.discnumber),
+        ("isrc", &mut info.isrc),
+    ] {
+        clean(field, value, &mut repairs);
+    }
+    if track_metadata.artist_name.is_empty() {
+        return Err(ValidationError::Missing("artist_name"));
+    }
+    if track_metadata.track_name.is_empty() {
+        return Err(ValidationError::Missing("track_name"));
+    }
+
+    for (field, value) in [
+        ("recording_mbid", &mut info.recording_mbid),
+        ("release_mbid", &mut info.release_mbid),
+        ("release_group_mbid", &mut info.release_group_mbid),
+        ("track_mbid", &mut info.track_mbid),
+    ] {
+        repair_mbid(field, value, &mut repairs);
+    }
+    for (field, values) in [
+        ("artist_mbids", &mut info.artist_mbids),
+        ("work_mbids", &mut info.work_mbids),
+    ] {
+        let mut mbids = Vec::with_capacity(values.len());
+        for mut value in std::mem::take(values) {
+            repair_mbid(field, &mut value, &mut repairs);
+            if !value.is_empty() && !mbids.contains(&value) {
+                mbids.push(value);
+            }
+        }
+        *values = mbids;
+    }
+
+    let mut tags = Vec::with_capacity(info.tags.len());
+    for mut tag in std::mem::take(&mut info.tags) {
+        clean("tag", &mut tag, &mut repairs);
+        if tag.chars().count() > MAX_TAG_SIZE {
+            repairs.push(Repair::Dropped("tag", tag));
+        } else if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if tags.len() > MAX_TAGS_PER_LISTEN {
+        repairs.push(Repair::DroppedTags(tags.len() - MAX_TAGS_PER_LISTEN));
+        tags.truncate(MAX_TAGS_PER_LISTEN);
+    }
+    info.tags = tags;
+
+    if info.duration_ms > MAX_DURATION_MS {
+        repairs.push(Repair::Dropped("duration_ms", info.duration_ms.to_string()));
+        info.duration_ms = 0;
+    }
+    if let Some(played) = info.played_duration_ms.filter(|&ms| ms > MAX_DURATION_MS) {
+        repairs.push(Repair::Dropped("played_duration_ms", played.to_string()));
+        info.played_duration_ms = None;
+    }
+
+    fit_size(payload, &mut repairs)?;
+    Ok(repairs)
+}
+
+/// Strips null characters, which the server refuses anywhere, and surrounding whitespace
+fn clean(field: &'static str, value: &mut String, repairs: &mut Vec<Repair>) {
+    let cleaned = value.replace('\0', "");
+    let cleaned = cleaned.trim();
+    if cleaned != value {
+        *value = cleaned.to_string();
+        repairs.push(Repair::Cleaned(field));
+    }
+}
+
+/// Reduces an MBID field to the MBID in it, or nothing when there isn't one
+fn repair_mbid(field: &'static str, value: &mut String, repairs: &mut Vec<Repair>) {
+    if value.is_empty() {
+        return;
+    }
+    let lowercase = value.to_ascii_lowercase();
+    match UUID_REGEX.get().unwrap().find(&lowercase) {
+        Some(mbid) if mbid.as_str() == value => {}
+        Some(mbid) if mbid.as_str() == lowercase.trim() => {
+            repairs.push(Repair::NormalizedMbid(field, std::mem::take(value)));
+            *value = mbid.as_str().to_string();
+        }
+        Some(mbid) => {
+            repairs.push(Repair::ExtractedMbid(field, std::mem::take(value)));
+            *value = mbid.as_str().to_string();
+        }
+        None => {
+            repairs.push(Repair::Dropped(field, std::mem::take(value)));
+        }
+    }
+}
+
+/// Optional fields dropped to fit the size limit, least useful first
+const DROPPABLE: [&str; 5] = [
+    "tags",
+    "work_mbids",
+    "isrc",
+    "release_artist_name",
+    "release_name",
+];
+
+/// Drops optional fields until the listen fits the server's size limit
+fn fit_size(payload: &mut Payload, repairs: &mut Vec<Repair>) -> Result<(), ValidationError> {
+    let mut droppable = DROPPABLE.into_iter();
+    loop {
+        let size = python_json_len(&serde_json::to_value(&*payload).unwrap());
+        if size <= MAX_LISTEN_SIZE {
+            return Ok(());
+        }
+        let Some(field) = droppable.next() else {
+            return Err(ValidationError::TooLarge(size));
+        };
+        let track_metadata = &mut payload.track_metadata;
+        let info = &mut track_metadata.additional_info;
+        match field {
+            "tags" => info.tags.clear(),
+            "work_mbids" => info.work_mbids.clear(),
+            "isrc" => info.isrc.clear(),
+            "release_artist_name" => info.release_artist_name.clear(),
+            _ => track_metadata.release_name.clear(),
+        }
+        repairs.push(Repair::DroppedForSize(field));
+    }
+}
+
+/// The length of `value` as Python's `json.dumps` writes it, which is what the server measures:
+/// spaces after separators and everything past ASCII escaped
+fn python_json_len(value: &serde_json::Value) -> usize {
+    use serde_json::Value;
+
+    let string_len = |text: &str| {
+        2 + text
+            .chars()
+            .map(|c| match c {
+                '"' | '\\' | '\n' | '\r' | '\t' | '\x08' | '\x0c' => 2,
+                ' '..='~' => 1,
+                c if c.len_utf16() == 2 => 12,
+                _ => 6,
+            })
+            .sum::<usize>()
+    };
+    let separators = |len: usize| 2 * len.saturating_sub(1);
+    match value {
+        Value::Null => 4,
+        Value::Bool(true) => 4,
+        Value::Bool(false) => 5,
+        Value::Number(number) => number.to_string().len(),
+        Value::String(text) => string_len(text),
+        Value::Array(values) => {
+            2 + values.iter().map(python_json_len).sum::<usize>() + separators(values.len())
+        }
+        Value::Object(fields) => {
+            2 + fields
+                .iter()
+                .map(|(key, value)| string_len(key) + 2 + python_json_len(value))
+                .sum::<usize>()
+                + separators(fields.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use regex::Regex;
+
+    use super::*;
+    use crate::UUID_PATTERN;
+
+    const MBID: &str = "b10bbbfc-cf9e-42e0-be17-e2c3e1d2600d";
+
+    fn listen(artist: &str, title: &str) -> Payload {
+        UUID_REGEX.get_or_init(|| Regex::new(UUID_PATTERN).unwrap());
+        let mut payload = Payload {
+            listened_at: NonZeroU64::new(1700000000),
+            ..Default::default()
+        };
+        payload.track_metadata.artist_name = artist.to_string();
+        payload.track_metadata.track_name = title.to_string();
+        payload
+    }
+
+    #[test]
+    fn extracts_mbids() {
+        let mut payload = listen("Artist", "Title");
+        let info = &mut payload.track_metadata.additional_info;
+        info.recording_mbid = format!("{}; other", MBID);
+        info.release_mbid = MBID.to_uppercase();
+        info.release_group_mbid = format!(" {}\n", MBID);
+        info.track_mbid = "not an mbid".to_string();
+        let repairs = validate("single", &mut payload).unwrap();
+
+        let info = &payload.track_metadata.additional_info;
+        assert_eq!(info.recording_mbid, MBID);
+        assert_eq!(info.release_mbid, MBID);
+        assert_eq!(info.release_group_mbid, MBID);
+        assert_eq!(info.track_mbid, "");
+        assert_eq!(
+            repairs,
+            [
+                Repair::ExtractedMbid("recording_mbid", format!("{}; other", MBID)),
+                Repair::NormalizedMbid("release_mbid", MBID.to_uppercase()),
+                Repair::NormalizedMbid("release_group_mbid", format!(" {}\n", MBID)),
+                Repair::Dropped("track_mbid", "not an mbid".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn dedups_mbid_lists() {
+        let mut payload = listen("Artist", "Title");
+        payload.track_metadata.additional_info.artist_mbids =
+            vec![MBID.to_string(), MBID.to_uppercase(), "garbage".to_string()];
+        validate("single", &mut payload).unwrap();
+        assert_eq!(payload.track_metadata.additional_info.artist_mbids, [MBID]);
+    }
+
+    #[test]
+    fn caps_tags() {
+        let mut payload = listen("Artist", "Title");
+        let mut tags: Vec<String> = (0..60).map(|i| format!("tag {}", i)).collect();
+        tags.insert(1, " tag 0 ".to_string());
+        tags.insert(2, "x".repeat(MAX_TAG_SIZE + 1));
+        payload.track_metadata.additional_info.tags = tags;
+        let repairs = validate("single", &mut payload).unwrap();
+
+        let tags = &payload.track_metadata.additional_info.tags;
+        assert_eq!(tags.len(), MAX_TAGS_PER_LISTEN);
+        assert_eq!(tags[..2], ["tag 0", "tag 1"]);
+        assert!(repairs.contains(&Repair::Dropped("tag", "x".repeat(MAX_TAG_SIZE + 1))));
+        assert!(repairs.contains(&Repair::DroppedTags(10)));
+    }
+
+    #[test]
+    fn checks_listened_at() {
+        let mut payload = listen("Artist", "Title");
+        payload.listened_at = NonZeroU64::new(LISTEN_MINIMUM_TS - 1);
+        assert_eq!(
+            validate("single", &mut payload),
+            Err(ValidationError::ListenedAt(Some(LISTEN_MINIMUM_TS - 1)))
+        );
+        payload.listened_at = None;
+        assert_eq!(
+            validate("import", &mut payload),
+            Err(ValidationError::ListenedAt(None))
+        );
+
+        // Playing now notifications mustn't have one
+        payload.listened_at = NonZeroU64::new(LISTEN_MINIMUM_TS - 1);
+        validate("playing_now", &mut payload).unwrap();
+        assert_eq!(payload.listened_at, None);
+    }
+
+    #[test]
+    fn requires_names() {
+        let mut payload = listen(" \0 ", "Title");
+        assert_eq!(
+            validate("single", &mut payload),
+            Err(ValidationError::Missing("artist_name"))
+        );
+    }
+
+    #[test]
+    fn measures_like_python() {
+        let value = serde_json::json!({ "a": "é😀\"\n\t", "b": [1, true, null], "c": {} });
+        // len(json.dumps(value))
+        assert_eq!(python_json_len(&value), 64);
+    }
+
+    #[test]
+    fn drops_least_useful_first() {
+        let mut payload = listen("Artist", "Title");
+        let track_metadata = &mut payload.track_metadata;
+        // Escaped to six bytes each by Python, only two in UTF-8
+        track_metadata.release_name = "é".repeat(1800);
+        track_metadata.additional_info.tags = vec!["tag".to_string()];
+        track_metadata.additional_info.work_mbids = vec![MBID.to_string()];
+        track_metadata.additional_info.isrc = "USRC17607839".to_string();
+        track_metadata.additional_info.release_artist_name = "Artist".to_string();
+        let repairs = validate("single", &mut payload).unwrap();
+        assert_eq!(
+            repairs,
+            DROPPABLE.map(Repair::DroppedForSize),
+            "dropped in the wrong order"
+        );
+
+        let mut payload = listen("Artist", "Title");
+        payload.track_metadata.additional_info.tags = (0..MAX_TAGS_PER_LISTEN)
+            .map(|i| format!("{:0>64}", i))
+            .collect();
+        payload.track_metadata.release_name = "é".repeat(1200);
+        let repairs = validate("single", &mut payload).unwrap();
+        assert_eq!(repairs, [Repair::DroppedForSize("tags")]);
+        assert!(!payload.track_metadata.release_name.is_empty());
+
+        let mut payload = listen("Artist", &"é".repeat(1800));
+        assert!(matches!(
+            validate("single", &mut payload),
+            Err(ValidationError::TooLarge(_))
+        ));
+    }
+}